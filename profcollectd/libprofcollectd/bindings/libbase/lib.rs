@@ -14,11 +14,13 @@
 // limitations under the License.
 //
 
-//! This module implements safe wrappers for GetProperty and SetProperty from libbase.
+//! This module implements safe wrappers for GetProperty and SetProperty from libbase, and for
+//! waiting on property changes.
 
-pub use ffi::{GetProperty, SetProperty};
+pub use ffi::{GetProperty, GetPropertySerial, SetProperty, WaitForPropertyChange};
 
-/// Safe wrappers for the GetProperty and SetProperty methods from libbase.
+/// Safe wrappers for the GetProperty and SetProperty methods from libbase, and for waiting on
+/// property changes with the system property serials.
 #[cxx::bridge]
 mod ffi {
     unsafe extern "C++" {
@@ -30,5 +32,13 @@ mod ffi {
 
         /// Sets the system property `key` to `value`.
         fn SetProperty(key: &str, value: &str);
+
+        /// Returns the serial of the system property `key`, which changes every time it is set,
+        /// or 0 if it doesn't exist.
+        fn GetPropertySerial(key: &str) -> u32;
+
+        /// Blocks until the serial of the system property `key` differs from `serial`, i.e. the
+        /// property is set or created, or `timeout_ms` elapses. Returns the current serial.
+        fn WaitForPropertyChange(key: &str, serial: u32, timeout_ms: u32) -> u32;
     }
 }
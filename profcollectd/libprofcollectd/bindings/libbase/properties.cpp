@@ -17,6 +17,11 @@
 #include "../../../../../libbase/include/android-base/properties.h"
 #include "properties.hpp"
 
+#include <sys/system_properties.h>
+#include <time.h>
+
+#include <chrono>
+
 rust::String GetProperty(rust::Str key, rust::Str default_value) {
   return android::base::GetProperty(std::string(key), std::string(default_value));
 }
@@ -24,3 +29,34 @@ rust::String GetProperty(rust::Str key, rust::Str default_value) {
 void SetProperty(rust::Str key, rust::Str value) {
   android::base::SetProperty(std::string(key), std::string(value));
 }
+
+uint32_t GetPropertySerial(rust::Str key) {
+  const prop_info* pi = __system_property_find(std::string(key).c_str());
+  return pi == nullptr ? 0 : __system_property_serial(pi);
+}
+
+uint32_t WaitForPropertyChange(rust::Str key, uint32_t serial, uint32_t timeout_ms) {
+  const std::string name(key);
+  const auto deadline = std::chrono::steady_clock::now() + std::chrono::milliseconds(timeout_ms);
+  while (true) {
+    // Read before looking the property up, so that its creation in between wakes us up.
+    uint32_t area_serial = __system_property_area_serial();
+    const prop_info* pi = __system_property_find(name.c_str());
+    uint32_t current = pi == nullptr ? 0 : __system_property_serial(pi);
+    if (current != serial) {
+      return current;
+    }
+
+    auto remaining = std::chrono::duration_cast<std::chrono::nanoseconds>(
+        deadline - std::chrono::steady_clock::now());
+    if (remaining.count() <= 0) {
+      return current;
+    }
+    timespec timeout;
+    timeout.tv_sec = static_cast<time_t>(remaining.count() / 1000000000);
+    timeout.tv_nsec = static_cast<long>(remaining.count() % 1000000000);
+    uint32_t new_serial;
+    // Without the property, wait for any property to change and look it up again.
+    __system_property_wait(pi, pi == nullptr ? area_serial : serial, &new_serial, &timeout);
+  }
+}
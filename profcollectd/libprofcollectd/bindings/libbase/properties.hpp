@@ -16,7 +16,11 @@
 
 #pragma once
 
+#include <stdint.h>
+
 #include "rust/cxx.h"
 
 rust::String GetProperty(rust::Str, rust::Str);
 void SetProperty(rust::Str, rust::Str);
+uint32_t GetPropertySerial(rust::Str);
+uint32_t WaitForPropertyChange(rust::Str, uint32_t, uint32_t);
@@ -0,0 +1,365 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Incrementally merge branch list profiles into one aggregated profile per binary.
+
+use anyhow::{Context, Result};
+use simpleperf_etm_branch_list::{Address, Binary, Branch, EtmBranchList};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fs::{remove_file, rename, write};
+use std::path::{Path, PathBuf};
+
+/// Prefix of the aggregated profiles, which are updated in place as traces are processed until
+/// they are reported.
+pub static MERGED_PROFILE_PREFIX: &str = "merged_";
+static MERGED_PROFILE_EXTENSION: &str = "data";
+
+/// Merge every binary in the branch list `profile` into its aggregated profile under
+/// `profile_dir`, summing counts for identical branch sequences, then delete `profile`.
+pub fn merge_branch_list(profile: &Path, profile_dir: &Path) -> Result<()> {
+    let new_profile = EtmBranchList::read_from_file(profile)
+        .with_context(|| format!("Failed to read profile {}", profile.display()))?;
+
+    // A profile may list the same binary more than once, so group it by aggregated profile first.
+    let mut new_binaries: BTreeMap<PathBuf, Binary> = BTreeMap::new();
+    for binary in new_profile.binaries {
+        let merged_path = get_merged_path(
+            profile_dir,
+            &binary.path,
+            &get_merged_key(&binary),
+            MERGED_PROFILE_EXTENSION,
+        );
+        match new_binaries.entry(merged_path) {
+            Entry::Vacant(entry) => {
+                entry.insert(binary);
+            }
+            Entry::Occupied(mut entry) => merge_addrs(&mut entry.get_mut().addrs, binary.addrs),
+        }
+    }
+
+    let mut updates = MergedProfiles::default();
+    for (merged_path, binary) in new_binaries {
+        let mut merged = match EtmBranchList::read_from_file(&merged_path) {
+            Ok(list) => list.binaries.into_iter().next().unwrap_or_else(|| empty_like(&binary)),
+            Err(simpleperf_etm_branch_list::Error::Io(e))
+                if e.kind() == std::io::ErrorKind::NotFound =>
+            {
+                empty_like(&binary)
+            }
+            Err(e) => {
+                // Start over rather than failing every future merge on a corrupted file.
+                log::error!("Discarding unreadable profile {}: {}", merged_path.display(), e);
+                empty_like(&binary)
+            }
+        };
+        merge_addrs(&mut merged.addrs, binary.addrs);
+
+        let merged = EtmBranchList { binaries: vec![merged] };
+        updates.write(merged_path, &merged.to_bytes())?;
+    }
+
+    updates.commit(profile)
+}
+
+/// Updated aggregated profiles, written next to the ones they replace. A merge writes all of
+/// them before replacing any, so that a failure leaves every aggregated profile as it was and the
+/// merged profile is kept to be merged again, rather than counted twice. Dropped updates that
+/// were not committed are deleted.
+#[derive(Default)]
+pub struct MergedProfiles {
+    /// Temporary and aggregated profile paths.
+    updates: Vec<(PathBuf, PathBuf)>,
+}
+
+impl MergedProfiles {
+    /// Write the new contents of the aggregated profile at `merged_path` aside.
+    pub fn write(&mut self, merged_path: PathBuf, contents: &[u8]) -> Result<()> {
+        let tmp_path = merged_path.with_extension("tmp");
+        self.updates.push((tmp_path.clone(), merged_path));
+        write(&tmp_path, contents)
+            .with_context(|| format!("Failed to write {}", tmp_path.display()))
+    }
+
+    /// Replace the aggregated profiles, then delete the `profile` merged into them.
+    pub fn commit(mut self, profile: &Path) -> Result<()> {
+        for (tmp_path, merged_path) in &self.updates {
+            rename(tmp_path, merged_path)
+                .with_context(|| format!("Failed to replace {}", merged_path.display()))?;
+        }
+        self.updates.clear();
+        remove_file(profile)?;
+        Ok(())
+    }
+}
+
+impl Drop for MergedProfiles {
+    fn drop(&mut self) {
+        for (tmp_path, _) in &self.updates {
+            remove_file(tmp_path).ok();
+        }
+    }
+}
+
+fn empty_like(binary: &Binary) -> Binary {
+    Binary {
+        path: binary.path.clone(),
+        build_id: binary.build_id.clone(),
+        addrs: Vec::new(),
+        binary_type: binary.binary_type,
+        kernel_start_addr: binary.kernel_start_addr,
+    }
+}
+
+fn merge_addrs(merged: &mut Vec<Address>, new: Vec<Address>) {
+    let mut counts: BTreeMap<u64, BTreeMap<(Vec<u8>, u32), u64>> = BTreeMap::new();
+    for addr in merged.drain(..).chain(new) {
+        let branches = counts.entry(addr.addr).or_default();
+        for branch in addr.branches {
+            let count = branches.entry((branch.branch, branch.branch_size)).or_default();
+            *count = count.saturating_add(branch.count);
+        }
+    }
+    merged.extend(counts.into_iter().map(|(addr, branches)| {
+        Address {
+            addr,
+            branches: branches
+                .into_iter()
+                .map(|((branch, branch_size), count)| Branch { branch, branch_size, count })
+                .collect(),
+        }
+    }));
+}
+
+/// Aggregated profiles are keyed by binary path and build id. Kernel addresses that have not
+/// been converted to vaddrs are only comparable under the same kernel start address, so that is
+/// part of the key too.
+fn get_merged_key(binary: &Binary) -> Vec<u8> {
+    let mut key = Vec::new();
+    key.extend_from_slice(binary.path.as_bytes());
+    key.push(0);
+    key.extend_from_slice(binary.build_id.as_bytes());
+    key.push(0);
+    key.extend_from_slice(&binary.kernel_start_addr.unwrap_or(0).to_le_bytes());
+    key
+}
+
+/// Path of the aggregated profile of `binary_path` under `profile_dir`, named after the binary
+/// and a hash of `key`.
+pub fn get_merged_path(
+    profile_dir: &Path,
+    binary_path: &str,
+    key: &[u8],
+    extension: &str,
+) -> PathBuf {
+    let basename: String = Path::new(binary_path)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or("unknown")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect();
+
+    // Not using set_extension(), the basename may contain dots.
+    let mut path = PathBuf::from(profile_dir);
+    path.push(format!("{}{}_{:016x}.{}", MERGED_PROFILE_PREFIX, basename, fnv1a(key), extension));
+    path
+}
+
+/// 64-bit FNV-1a, used because file names must stay stable across releases.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter()
+        .fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simpleperf_etm_branch_list::BinaryType;
+    use std::fs::{create_dir, read_dir};
+
+    fn binary(path: &str, build_id: &str, kernel_start_addr: Option<u64>) -> Binary {
+        Binary {
+            path: path.to_string(),
+            build_id: build_id.to_string(),
+            addrs: Vec::new(),
+            binary_type: if kernel_start_addr.is_some() {
+                BinaryType::Kernel
+            } else {
+                BinaryType::ElfFile
+            },
+            kernel_start_addr,
+        }
+    }
+
+    fn with_branches(mut binary: Binary, branches: &[(u64, u8, u64)]) -> Binary {
+        for (addr, branch, count) in branches {
+            binary.addrs.push(Address {
+                addr: *addr,
+                branches: vec![Branch { branch: vec![*branch], branch_size: 8, count: *count }],
+            });
+        }
+        binary
+    }
+
+    fn merged_path(profile_dir: &Path, binary: &Binary) -> PathBuf {
+        get_merged_path(
+            profile_dir,
+            &binary.path,
+            &get_merged_key(binary),
+            MERGED_PROFILE_EXTENSION,
+        )
+    }
+
+    fn merge(trace_dir: &Path, profile_dir: &Path, binaries: Vec<Binary>) -> Result<()> {
+        let profile = trace_dir.join("trace.data");
+        EtmBranchList { binaries }.write_to_file(&profile).unwrap();
+        merge_branch_list(&profile, profile_dir)
+    }
+
+    fn read_merged(profile_dir: &Path, binary: &Binary) -> Binary {
+        let list = EtmBranchList::read_from_file(&merged_path(profile_dir, binary)).unwrap();
+        assert_eq!(list.binaries.len(), 1);
+        list.binaries.into_iter().next().unwrap()
+    }
+
+    fn count_files(dir: &Path) -> usize {
+        read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn counts_summed() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let libc = binary("/system/lib64/libc.so", "abc", None);
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            vec![with_branches(libc.clone(), &[(0x100, 1, 2), (0x200, 1, 5)])],
+        )
+        .unwrap();
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            vec![with_branches(libc.clone(), &[(0x100, 1, 3), (0x100, 0, 1), (0x300, 1, 1)])],
+        )
+        .unwrap();
+
+        assert_eq!(count_files(trace_dir.path()), 0);
+        assert_eq!(count_files(profile_dir.path()), 1);
+        let merged = read_merged(profile_dir.path(), &libc);
+        assert_eq!(merged.path, libc.path);
+        assert_eq!(merged.build_id, libc.build_id);
+        let counts: Vec<_> = merged
+            .addrs
+            .iter()
+            .flat_map(|a| a.branches.iter().map(move |b| (a.addr, b.branch[0], b.count)))
+            .collect();
+        assert_eq!(counts, vec![(0x100, 0, 1), (0x100, 1, 5), (0x200, 1, 5), (0x300, 1, 1)]);
+    }
+
+    #[test]
+    fn duplicate_binaries_summed() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let libc = binary("/system/lib64/libc.so", "abc", None);
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            vec![
+                with_branches(libc.clone(), &[(0x100, 1, 2)]),
+                with_branches(libc.clone(), &[(0x100, 1, 3), (0x200, 1, 1)]),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(count_files(profile_dir.path()), 1);
+        let merged = read_merged(profile_dir.path(), &libc);
+        let counts: Vec<_> = merged.addrs.iter().map(|a| (a.addr, a.branches[0].count)).collect();
+        assert_eq!(counts, vec![(0x100, 5), (0x200, 1)]);
+    }
+
+    #[test]
+    fn keys_kept_separate() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let binaries = vec![
+            binary("/system/lib64/libc.so", "abc", None),
+            binary("/system/lib64/libc.so", "def", None),
+            binary("[kernel.kallsyms]", "", Some(0xffffffc010000000)),
+            binary("[kernel.kallsyms]", "", Some(0xffffffc020000000)),
+        ];
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            binaries.iter().map(|b| with_branches(b.clone(), &[(0x100, 1, 1)])).collect(),
+        )
+        .unwrap();
+
+        assert_eq!(count_files(profile_dir.path()), binaries.len());
+        for binary in &binaries {
+            let merged = read_merged(profile_dir.path(), binary);
+            assert_eq!(merged.build_id, binary.build_id);
+            assert_eq!(merged.kernel_start_addr, binary.kernel_start_addr);
+            assert_eq!(merged.addrs[0].branches[0].count, 1);
+        }
+    }
+
+    #[test]
+    fn corrupt_merged_profile_discarded() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let libc = binary("/system/lib64/libc.so", "abc", None);
+        write(merged_path(profile_dir.path(), &libc), b"garbage").unwrap();
+
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            vec![with_branches(libc.clone(), &[(0x100, 1, 2)])],
+        )
+        .unwrap();
+        let merged = read_merged(profile_dir.path(), &libc);
+        assert_eq!(merged.addrs.len(), 1);
+        assert_eq!(merged.addrs[0].branches[0].count, 2);
+    }
+
+    #[test]
+    fn failed_merge_changes_nothing() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let libc = binary("/system/lib64/libc.so", "abc", None);
+        let libm = binary("/system/lib64/libm.so", "def", None);
+        merge(
+            trace_dir.path(),
+            profile_dir.path(),
+            vec![with_branches(libc.clone(), &[(0x100, 1, 2)])],
+        )
+        .unwrap();
+
+        // Make writing the update of the second binary fail.
+        create_dir(merged_path(profile_dir.path(), &libm).with_extension("tmp")).unwrap();
+        let new_binaries = vec![
+            with_branches(libc.clone(), &[(0x100, 1, 3)]),
+            with_branches(libm, &[(0x100, 1, 1)]),
+        ];
+        assert!(merge(trace_dir.path(), profile_dir.path(), new_binaries).is_err());
+
+        // The trace is kept to be merged again, and the first binary was not updated.
+        assert!(trace_dir.path().join("trace.data").exists());
+        assert_eq!(read_merged(profile_dir.path(), &libc).addrs[0].branches[0].count, 2);
+        assert!(!merged_path(profile_dir.path(), &libc).with_extension("tmp").exists());
+    }
+}
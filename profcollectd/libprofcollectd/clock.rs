@@ -0,0 +1,154 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Source of time for the scheduler, trace names and report names, replaced by a virtual clock in
+//! tests.
+
+use chrono::{DateTime, FixedOffset, Local, TimeZone, Utc};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+    /// Offset of the device time zone from UTC at `t`.
+    fn local_offset(&self, t: DateTime<Utc>) -> FixedOffset;
+    /// Block for `timeout`, or until a message is received on `cancel` or its sender is dropped.
+    /// Returns whether the wait was cancelled.
+    fn sleep(&self, timeout: Duration, cancel: &Receiver<()>) -> bool;
+}
+
+/// The system wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn local_offset(&self, t: DateTime<Utc>) -> FixedOffset {
+        Local.offset_from_utc_datetime(&t.naive_utc())
+    }
+
+    fn sleep(&self, timeout: Duration, cancel: &Receiver<()>) -> bool {
+        !matches!(cancel.recv_timeout(timeout), Err(RecvTimeoutError::Timeout))
+    }
+}
+
+#[cfg(test)]
+pub use virtual_clock::VirtualClock;
+
+#[cfg(test)]
+mod virtual_clock {
+    use super::Clock;
+    use chrono::{DateTime, FixedOffset, Utc};
+    use std::sync::mpsc::{Receiver, TryRecvError};
+    use std::sync::{Condvar, Mutex};
+    use std::time::Duration;
+
+    /// How often sleepers check for cancellation, in real time.
+    const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(5);
+    /// How long tests wait for worker threads, in real time, before giving up.
+    const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn to_chrono(duration: Duration) -> chrono::Duration {
+        chrono::Duration::from_std(duration).unwrap()
+    }
+
+    struct State {
+        now: DateTime<Utc>,
+        local_offset: FixedOffset,
+        /// Number of calls to sleep() so far.
+        sleeps: u64,
+    }
+
+    /// A clock that only moves forward when told to, letting tests step worker threads through
+    /// their sleeps deterministically.
+    pub struct VirtualClock {
+        state: Mutex<State>,
+        changed: Condvar,
+    }
+
+    impl VirtualClock {
+        pub fn new(now: DateTime<Utc>) -> Self {
+            let local_offset = FixedOffset::east_opt(0).unwrap();
+            VirtualClock {
+                state: Mutex::new(State { now, local_offset, sleeps: 0 }),
+                changed: Condvar::new(),
+            }
+        }
+
+        /// Move the device to a time zone `offset` from UTC. The clock starts in UTC.
+        pub fn set_local_offset(&self, offset: FixedOffset) {
+            self.state.lock().unwrap().local_offset = offset;
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            let mut state = self.state.lock().unwrap();
+            state.now = state.now.checked_add_signed(to_chrono(duration)).unwrap();
+            self.changed.notify_all();
+        }
+
+        pub fn sleeps(&self) -> u64 {
+            self.state.lock().unwrap().sleeps
+        }
+
+        /// Block until sleep() has been called `count` times in total. Panics after
+        /// SETTLE_TIMEOUT.
+        pub fn wait_for_sleeps(&self, count: u64) {
+            let (_state, result) = self
+                .changed
+                .wait_timeout_while(self.state.lock().unwrap(), SETTLE_TIMEOUT, |state| {
+                    state.sleeps < count
+                })
+                .unwrap();
+            assert!(!result.timed_out(), "Timed out waiting for sleep #{}", count);
+        }
+
+        /// Advance by `duration` and wait for the sleeping worker to go back to sleep.
+        pub fn advance_and_settle(&self, duration: Duration) {
+            let sleeps = self.sleeps();
+            self.advance(duration);
+            self.wait_for_sleeps(sleeps + 1);
+        }
+    }
+
+    impl Clock for VirtualClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.state.lock().unwrap().now
+        }
+
+        fn local_offset(&self, _t: DateTime<Utc>) -> FixedOffset {
+            self.state.lock().unwrap().local_offset
+        }
+
+        fn sleep(&self, timeout: Duration, cancel: &Receiver<()>) -> bool {
+            let mut state = self.state.lock().unwrap();
+            let deadline = state.now + to_chrono(timeout);
+            state.sleeps += 1;
+            self.changed.notify_all();
+            loop {
+                match cancel.try_recv() {
+                    Ok(()) | Err(TryRecvError::Disconnected) => return true,
+                    Err(TryRecvError::Empty) => (),
+                }
+                if state.now >= deadline {
+                    return false;
+                }
+                state = self.changed.wait_timeout(state, CANCEL_POLL_INTERVAL).unwrap().0;
+            }
+        }
+    }
+}
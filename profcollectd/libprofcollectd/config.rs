@@ -16,12 +16,13 @@
 
 //! ProfCollect configurations.
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 use macaddr::MacAddr6;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::fmt;
 use std::fs::{read_dir, remove_file};
 use std::path::Path;
 use std::str::FromStr;
@@ -40,8 +41,23 @@ lazy_static! {
     pub static ref BETTERBUG_CACHE_DIR_PREFIX: &'static Path = Path::new("/data/user/");
     pub static ref BETTERBUG_CACHE_DIR_SUFFIX: &'static Path =
         Path::new("com.google.android.apps.internal.betterbug/cache/");
+    /// Directories reports may be exported to, along with their subdirectories, see report_sinks.
+    /// A "*" component matches any user id. Export directories are checked again once their
+    /// symlinks are resolved, so this also lists the /data/data/ directories, which /data/user/0/
+    /// links to.
+    pub static ref EXPORT_DIR_ALLOWLIST: Vec<&'static Path> = vec![
+        Path::new("/data/user/*/com.google.android.apps.internal.betterbug/cache/"),
+        Path::new("/data/data/com.google.android.apps.internal.betterbug/cache/"),
+    ];
     pub static ref CONFIG_FILE: &'static Path =
         Path::new("/data/misc/profcollectd/output/config.json");
+    pub static ref SIGNING_KEY_FILE: &'static Path =
+        Path::new("/data/misc/profcollectd/signing_key");
+    pub static ref REPORT_INDEX_FILE: &'static Path =
+        Path::new("/data/misc/profcollectd/report_index.json");
+    pub static ref UPLOAD_STATE_FILE: &'static Path =
+        Path::new("/data/misc/profcollectd/upload_state.json");
+    pub static ref SYSTEM_CA_CERTS_DIR: &'static Path = Path::new("/system/etc/security/cacerts/");
 }
 
 /// Dynamic configs, stored in config.json.
@@ -57,10 +73,173 @@ pub struct Config {
     pub collection_interval: Duration,
     /// Length of time each collection lasts for.
     pub sampling_period: Duration,
-    /// An optional filter to limit which binaries to or not to profile.
+    /// Randomisation of the interval between periodic collections.
+    pub collection_jitter: CollectionJitter,
+    /// Daily time windows periodic collections are restricted to, in the device time zone, as
+    /// "HH:MM-HH:MM" separated by ';', e.g. "22:00-02:00;12:00-13:00". Collections are allowed at
+    /// any time if empty.
+    pub collection_windows: String,
+    /// Maximum number of periodic collections per day in the device time zone. 0 means no limit.
+    pub max_daily_traces: u32,
+    /// Only trace while the battery is charging or full.
+    pub require_charging: bool,
+    /// Minimum battery capacity to trace, in percent. 0 disables the check.
+    pub min_battery_capacity: u32,
+    /// Maximum temperature of any thermal zone to trace, in degrees Celsius. 0 disables the
+    /// check.
+    pub max_thermal_temp: u32,
+    /// Maximum CPU pressure to trace, as the percentage of time some tasks were stalled on CPU
+    /// over the last 10 seconds. 0 disables the check, which pressure triggers always skip.
+    pub max_cpu_pressure: u32,
+    /// Maximum 1 minute load average to trace. 0 disables the check, which pressure triggers
+    /// always skip.
+    pub max_load_average: u32,
+    /// CPU pressure triggering a one-off trace tagged "psi_cpu", as the percentage of two seconds
+    /// some tasks were stalled on CPU for. 0 disables the trigger.
+    pub cpu_pressure_trigger: u32,
+    /// IO pressure triggering a one-off trace tagged "psi_io", as for cpu_pressure_trigger.
+    pub io_pressure_trigger: u32,
+    /// Minimum time between two pressure triggered traces, and between two traces triggered by
+    /// the same property.
+    pub trigger_cooldown: Duration,
+    /// System properties separated by ',' that trigger a one-off trace whenever set, tagged with
+    /// their new value, e.g. "sys.profcollectd.trigger".
+    pub trigger_properties: String,
+    /// An optional regex of the binaries to profile, e.g. "^/system/".
     pub binary_filter: String,
+    /// An optional regex of the binaries not to profile, even if they match binary_filter.
+    pub binary_exclude_filter: String,
     /// Maximum size of the trace directory.
     pub max_trace_limit: u64,
+    /// Maximum size of a single report file. Profiles that don't fit are split across several
+    /// report files. 0 means no limit.
+    pub max_report_size: u64,
+    /// Compression of the profiles in reports.
+    pub report_compression: ReportCompression,
+    /// Hex encoded X25519 public keys separated by ',' that reports are encrypted to. Reports are
+    /// not encrypted if empty.
+    pub report_public_keys: String,
+    /// How report set ids are generated.
+    pub report_naming: ReportNaming,
+    /// Destinations of export_report, see report_sink::parse_report_sinks().
+    pub report_sinks: String,
+    /// HTTP(S) endpoint reports are uploaded to as they are created, see uploader. Reports are not
+    /// uploaded if empty.
+    pub upload_url: String,
+    /// Maximum number of bytes uploaded per day in the device time zone.
+    pub upload_daily_budget: u64,
+}
+
+/// Distribution of the interval between periodic collections, set through the
+/// "collection_jitter" flag as "none", "uniform", "uniform:<percent>" (0-100) or "exponential".
+/// Jitter keeps devices booted together from tracing in lockstep, and from always sampling the
+/// same phase of periodic workloads. Jittered intervals are never shorter than a tenth of
+/// collection_interval.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum CollectionJitter {
+    /// Exactly collection_interval.
+    #[default]
+    None,
+    /// Uniformly distributed within the given percentage of collection_interval, either way.
+    Uniform(u32),
+    /// Exponentially distributed with collection_interval as mean, i.e. collections form a
+    /// Poisson process.
+    Exponential,
+}
+
+const DEFAULT_UNIFORM_JITTER_PERCENT: u32 = 50;
+
+impl fmt::Display for CollectionJitter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CollectionJitter::None => write!(f, "none"),
+            CollectionJitter::Uniform(percent) => write!(f, "uniform:{}", percent),
+            CollectionJitter::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+impl FromStr for CollectionJitter {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(CollectionJitter::None),
+            None if s == "uniform" => Ok(CollectionJitter::Uniform(DEFAULT_UNIFORM_JITTER_PERCENT)),
+            None if s == "exponential" => Ok(CollectionJitter::Exponential),
+            Some(("uniform", percent)) => match percent.parse::<u32>()? {
+                percent if percent <= 100 => Ok(CollectionJitter::Uniform(percent)),
+                _ => bail!("Jitter percentage out of range: {}", s),
+            },
+            _ => bail!("Unknown collection jitter: {}", s),
+        }
+    }
+}
+
+/// Scheme of report set ids, set through the "report_naming" flag as "uuid1", "uuid4" or "uuid7".
+/// The node id and creation time are recorded in the report manifest whatever the scheme.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ReportNaming {
+    /// Legacy time-based UUID holding the node id.
+    Uuid1,
+    /// Random UUID.
+    Uuid4,
+    /// Random UUID prefixed with the creation time in milliseconds.
+    #[default]
+    Uuid7,
+}
+
+impl fmt::Display for ReportNaming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReportNaming::Uuid1 => write!(f, "uuid1"),
+            ReportNaming::Uuid4 => write!(f, "uuid4"),
+            ReportNaming::Uuid7 => write!(f, "uuid7"),
+        }
+    }
+}
+
+impl FromStr for ReportNaming {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uuid1" => Ok(ReportNaming::Uuid1),
+            "uuid4" => Ok(ReportNaming::Uuid4),
+            "uuid7" => Ok(ReportNaming::Uuid7),
+            _ => bail!("Unknown report naming: {}", s),
+        }
+    }
+}
+
+/// Compression method of report archives, set through the "report_compression" flag as "stored"
+/// or "deflate". Zstandard is not supported, as the platform zip crate is built without it, and
+/// neither are compression levels, which it does not expose.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub enum ReportCompression {
+    /// No compression, for devices where CPU time matters more than storage.
+    Stored,
+    /// Readable by any zip tool.
+    #[default]
+    Deflate,
+}
+
+impl fmt::Display for ReportCompression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReportCompression::Stored => write!(f, "stored"),
+            ReportCompression::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+impl FromStr for ReportCompression {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stored" => Ok(ReportCompression::Stored),
+            "deflate" => Ok(ReportCompression::Deflate),
+            _ => bail!("Unknown report compression: {}", s),
+        }
+    }
 }
 
 impl Config {
@@ -74,13 +253,84 @@ impl Config {
                 600,
             )?),
             sampling_period: Duration::from_millis(get_device_config("sampling_period", 500)?),
+            collection_jitter: get_parsed_device_config(
+                "collection_jitter",
+                CollectionJitter::default(),
+            )?,
+            collection_windows: get_device_config("collection_windows", "".to_string())?,
+            max_daily_traces: get_device_config("max_daily_traces", 0)?,
+            require_charging: get_device_config("require_charging", false)?,
+            min_battery_capacity: get_device_config("min_battery_capacity", 0)?,
+            max_thermal_temp: get_device_config("max_thermal_temp", 0)?,
+            max_cpu_pressure: get_device_config("max_cpu_pressure", 0)?,
+            max_load_average: get_device_config("max_load_average", 0)?,
+            cpu_pressure_trigger: get_device_config("cpu_pressure_trigger", 0)?,
+            io_pressure_trigger: get_device_config("io_pressure_trigger", 0)?,
+            trigger_cooldown: Duration::from_secs(get_device_config("trigger_cooldown", 1800)?),
+            trigger_properties: get_device_config("trigger_properties", "".to_string())?,
             binary_filter: get_device_config("binary_filter", "".to_string())?,
+            binary_exclude_filter: get_device_config("binary_exclude_filter", "".to_string())?,
             max_trace_limit: get_device_config(
                 "max_trace_limit",
                 /* 512MB */ 512 * 1024 * 1024,
             )?,
+            max_report_size: get_device_config("max_report_size", 0)?,
+            report_compression: get_parsed_device_config(
+                "report_compression",
+                ReportCompression::default(),
+            )?,
+            report_public_keys: get_device_config("report_public_keys", "".to_string())?,
+            report_naming: get_parsed_device_config("report_naming", ReportNaming::default())?,
+            report_sinks: get_device_config("report_sinks", "".to_string())?,
+            upload_url: get_device_config("upload_url", "".to_string())?,
+            upload_daily_budget: get_device_config(
+                "upload_daily_budget",
+                /* 64MB */ 64 * 1024 * 1024,
+            )?,
         })
     }
+
+    /// Whether switching from `self` to `new_config` invalidates the traces and profiles collected
+    /// so far, e.g. because they were collected on a different build.
+    pub fn requires_data_reset(&self, new_config: &Config) -> bool {
+        self.version != new_config.version || self.build_fingerprint != new_config.build_fingerprint
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The default configuration, without reading the device properties and flags.
+    pub fn for_test() -> Self {
+        Config {
+            version: 1,
+            node_id: MacAddr6::nil(),
+            build_fingerprint: "test".to_string(),
+            collection_interval: Duration::from_secs(600),
+            sampling_period: Duration::from_millis(500),
+            collection_jitter: CollectionJitter::default(),
+            collection_windows: "".to_string(),
+            max_daily_traces: 0,
+            require_charging: false,
+            min_battery_capacity: 0,
+            max_thermal_temp: 0,
+            max_cpu_pressure: 0,
+            max_load_average: 0,
+            cpu_pressure_trigger: 0,
+            io_pressure_trigger: 0,
+            trigger_cooldown: Duration::from_secs(1800),
+            trigger_properties: "".to_string(),
+            binary_filter: "".to_string(),
+            binary_exclude_filter: "".to_string(),
+            max_trace_limit: 512 * 1024 * 1024,
+            max_report_size: 0,
+            report_compression: ReportCompression::default(),
+            report_public_keys: "".to_string(),
+            report_naming: ReportNaming::default(),
+            report_sinks: "".to_string(),
+            upload_url: "".to_string(),
+            upload_daily_budget: 64 * 1024 * 1024,
+        }
+    }
 }
 
 impl ToString for Config {
@@ -96,6 +346,18 @@ impl FromStr for Config {
     }
 }
 
+/// An invalid flag falls back to the default value rather than failing to start.
+fn get_parsed_device_config<T>(key: &str, default_value: T) -> Result<T>
+where
+    T: FromStr<Err = anyhow::Error> + fmt::Display + Copy,
+{
+    let flag = get_device_config(key, default_value.to_string())?;
+    Ok(flag.parse().unwrap_or_else(|e| {
+        log::error!("Invalid {} flag, using default: {}", key, e);
+        default_value
+    }))
+}
+
 fn get_or_initialise_node_id() -> Result<MacAddr6> {
     let mut node_id = get_property(&PROFCOLLECT_NODE_ID_PROPERTY, MacAddr6::nil())?;
     if node_id.is_nil() {
@@ -161,5 +423,28 @@ pub fn clear_data() -> Result<()> {
     remove_files(&TRACE_OUTPUT_DIR)?;
     remove_files(&PROFILE_OUTPUT_DIR)?;
     remove_files(&REPORT_OUTPUT_DIR)?;
+    if REPORT_INDEX_FILE.exists() {
+        remove_file(*REPORT_INDEX_FILE)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_compression_round_trip() {
+        for compression in &[ReportCompression::Stored, ReportCompression::Deflate] {
+            assert_eq!(compression.to_string().parse::<ReportCompression>().unwrap(), *compression);
+        }
+        assert_eq!(ReportCompression::default().to_string(), "deflate");
+    }
+
+    #[test]
+    fn report_compression_rejected() {
+        for s in &["", "gzip", "zstd", "zstd:3", "stored:1", "deflate:", "deflate:6"] {
+            assert!(s.parse::<ReportCompression>().is_err(), "{}", s);
+        }
+    }
+}
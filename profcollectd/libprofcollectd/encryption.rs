@@ -0,0 +1,390 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Encryption of reports to one or more X25519 public keys.
+//!
+//! An encrypted report is an envelope around the report zip, laid out as follows. Integers are
+//! big endian.
+//!
+//! ```text
+//! magic           8 bytes    "PCRPTENC"
+//! version         1 byte     1
+//! recipients      1 byte     number of recipients N, at least 1
+//! ephemeral key   32 bytes   X25519 public key generated for this report
+//! N times:
+//!   key id        8 bytes    first 8 bytes of SHA-256 of the recipient public key
+//!   wrapped key   48 bytes   file key encrypted with ChaCha20-Poly1305
+//! chunks          the zip encrypted with ChaCha20-Poly1305 under the file key
+//! ```
+//!
+//! The file key is a random 256-bit key. For each recipient, it is wrapped under the key derived
+//! by HKDF-SHA256 from the X25519 shared secret between the ephemeral key and the recipient key,
+//! with the ephemeral public key followed by the recipient public key as salt and
+//! "profcollect report key wrap v1" as info. The wrapping nonce is all zeroes, as each wrapping
+//! key is used only once.
+//!
+//! The zip is split into chunks of 64 KiB. The last chunk is shorter, except when the zip size is a
+//! multiple of 64 KiB, in which case it is full, or empty if the zip is. Each chunk is encrypted
+//! into its ciphertext followed by a 16 byte tag, with the SHA-256 of the header as associated
+//! data. The nonce of chunk i is 3 zero bytes, i as 8 bytes, then 1 for the last chunk or 0
+//! otherwise, so that chunks cannot be reordered, dropped or truncated.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::manifest::from_hex;
+
+pub const ENVELOPE_MAGIC: &[u8; 8] = b"PCRPTENC";
+const ENVELOPE_VERSION: u8 = 1;
+const KEY_WRAP_INFO: &[u8] = b"profcollect report key wrap v1";
+const KEY_ID_SIZE: usize = 8;
+const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Parse a list of hex encoded X25519 public keys separated by ','.
+pub fn parse_public_keys(keys: &str) -> Result<Vec<PublicKey>> {
+    keys.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(|key| Ok(PublicKey::from(parse_key(key)?)))
+        .collect()
+}
+
+fn parse_key(key: &str) -> Result<[u8; KEY_SIZE]> {
+    let bytes = from_hex(key).with_context(|| format!("Invalid key: {}", key))?;
+    ensure!(bytes.len() == KEY_SIZE, "Invalid key: {}", key);
+    let mut key = [0u8; KEY_SIZE];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+fn get_key_id(key: &PublicKey) -> [u8; KEY_ID_SIZE] {
+    let mut key_id = [0u8; KEY_ID_SIZE];
+    key_id.copy_from_slice(&Sha256::digest(key.as_bytes())[..KEY_ID_SIZE]);
+    key_id
+}
+
+fn get_wrapping_cipher(
+    shared_secret: &[u8],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> ChaCha20Poly1305 {
+    let mut salt = Vec::with_capacity(KEY_SIZE * 2);
+    salt.extend_from_slice(ephemeral.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut key = [0u8; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(KEY_WRAP_INFO, &mut key)
+        .expect("Key size is a valid HKDF output length");
+    ChaCha20Poly1305::new(&Key::from(key))
+}
+
+fn get_chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+/// Read until `buffer` is full or the end of input, returning the number of bytes read.
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match input.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
+
+/// Encrypt `input` to each of `recipients`, writing the envelope to `output`.
+pub fn encrypt(input: impl Read, output: impl Write, recipients: &[PublicKey]) -> Result<()> {
+    ensure!(!recipients.is_empty(), "No recipient to encrypt to");
+    ensure!(recipients.len() <= u8::MAX as usize, "Too many recipients");
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+
+    let mut rng = rand::thread_rng();
+    let mut secret = [0u8; KEY_SIZE];
+    rng.fill_bytes(&mut secret);
+    let ephemeral_secret = StaticSecret::from(secret);
+    let ephemeral = PublicKey::from(&ephemeral_secret);
+    let mut file_key = [0u8; KEY_SIZE];
+    rng.fill_bytes(&mut file_key);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(ENVELOPE_MAGIC);
+    header.push(ENVELOPE_VERSION);
+    header.push(recipients.len() as u8);
+    header.extend_from_slice(ephemeral.as_bytes());
+    for recipient in recipients {
+        let shared_secret = ephemeral_secret.diffie_hellman(recipient);
+        let wrapped_key = get_wrapping_cipher(shared_secret.as_bytes(), &ephemeral, recipient)
+            .encrypt(&Nonce::default(), &file_key[..])
+            .map_err(|_| anyhow!("Failed to wrap file key"))?;
+        header.extend_from_slice(&get_key_id(recipient));
+        header.extend_from_slice(&wrapped_key);
+    }
+    output.write_all(&header)?;
+
+    let aad = Sha256::digest(&header);
+    let cipher = ChaCha20Poly1305::new(&Key::from(file_key));
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut index = 0u64;
+    loop {
+        let len = read_full(&mut input, &mut chunk)?;
+        let last = len < CHUNK_SIZE || input.fill_buf()?.is_empty();
+        let ciphertext = cipher
+            .encrypt(&get_chunk_nonce(index, last), Payload { msg: &chunk[..len], aad: &aad })
+            .map_err(|_| anyhow!("Failed to encrypt report"))?;
+        output.write_all(&ciphertext)?;
+        if last {
+            break;
+        }
+        index += 1;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Decrypt an envelope read from `input` with the X25519 `secret_key` of one of its recipients,
+/// writing the report zip to `output`. Fails if the envelope was tampered with or truncated, in
+/// which case what was written to `output` must be discarded.
+pub fn decrypt(input: impl Read, output: impl Write, secret_key: &[u8; KEY_SIZE]) -> Result<()> {
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+
+    let mut header = vec![0u8; ENVELOPE_MAGIC.len() + 2 + KEY_SIZE];
+    input.read_exact(&mut header).context("Truncated envelope header")?;
+    ensure!(&header[..ENVELOPE_MAGIC.len()] == ENVELOPE_MAGIC, "Not an encrypted report");
+    let version = header[ENVELOPE_MAGIC.len()];
+    ensure!(version == ENVELOPE_VERSION, "Unsupported envelope version {}", version);
+    let recipients = header[ENVELOPE_MAGIC.len() + 1] as usize;
+    let mut ephemeral = [0u8; KEY_SIZE];
+    ephemeral.copy_from_slice(&header[header.len() - KEY_SIZE..]);
+    let ephemeral = PublicKey::from(ephemeral);
+
+    let secret = StaticSecret::from(*secret_key);
+    let public = PublicKey::from(&secret);
+    let key_id = get_key_id(&public);
+    let mut file_key = None;
+    for _ in 0..recipients {
+        let mut entry = [0u8; KEY_ID_SIZE + KEY_SIZE + TAG_SIZE];
+        input.read_exact(&mut entry).context("Truncated envelope header")?;
+        header.extend_from_slice(&entry);
+        if file_key.is_some() || entry[..KEY_ID_SIZE] != key_id {
+            continue;
+        }
+        let shared_secret = secret.diffie_hellman(&ephemeral);
+        file_key = get_wrapping_cipher(shared_secret.as_bytes(), &ephemeral, &public)
+            .decrypt(&Nonce::default(), &entry[KEY_ID_SIZE..])
+            .ok();
+    }
+    let file_key = match file_key {
+        Some(key) if key.len() == KEY_SIZE => {
+            let mut file_key = [0u8; KEY_SIZE];
+            file_key.copy_from_slice(&key);
+            Key::from(file_key)
+        }
+        _ => bail!("Report is not encrypted to this key"),
+    };
+
+    let aad = Sha256::digest(&header);
+    let cipher = ChaCha20Poly1305::new(&file_key);
+    let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+    let mut index = 0u64;
+    loop {
+        let len = read_full(&mut input, &mut chunk)?;
+        let last = len < chunk.len() || input.fill_buf()?.is_empty();
+        let plaintext = cipher
+            .decrypt(&get_chunk_nonce(index, last), Payload { msg: &chunk[..len], aad: &aad })
+            .map_err(|_| anyhow!("Corrupted or truncated report"))?;
+        output.write_all(&plaintext)?;
+        if last {
+            break;
+        }
+        index += 1;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Decrypt the encrypted report `report` into the zip `output`, given the hex encoded X25519
+/// secret key of one of its recipients.
+pub fn decrypt_report(report: &Path, output: &Path, secret_key: &str) -> Result<()> {
+    let secret_key = parse_key(secret_key)?;
+    let input = File::open(report)?;
+    let result = File::create(output)
+        .map_err(anyhow::Error::from)
+        .and_then(|f| decrypt(input, f, &secret_key));
+    if result.is_err() {
+        std::fs::remove_file(output).ok();
+    }
+    result.with_context(|| format!("Failed to decrypt report {}", report.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_key(seed: u8) -> [u8; KEY_SIZE] {
+        [seed; KEY_SIZE]
+    }
+
+    fn public_key(seed: u8) -> PublicKey {
+        PublicKey::from(&StaticSecret::from(secret_key(seed)))
+    }
+
+    fn plaintext(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn encrypt_to(plaintext: &[u8], recipients: &[PublicKey]) -> Vec<u8> {
+        let mut envelope = Vec::new();
+        encrypt(plaintext, &mut envelope, recipients).unwrap();
+        envelope
+    }
+
+    fn decrypt_with(envelope: &[u8], seed: u8) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        decrypt(envelope, &mut plaintext, &secret_key(seed))?;
+        Ok(plaintext)
+    }
+
+    fn header_size(recipients: usize) -> usize {
+        ENVELOPE_MAGIC.len() + 2 + KEY_SIZE + recipients * (KEY_ID_SIZE + KEY_SIZE + TAG_SIZE)
+    }
+
+    #[test]
+    fn round_trip() {
+        for len in &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE] {
+            let plaintext = plaintext(*len);
+            let envelope = encrypt_to(&plaintext, &[public_key(1)]);
+            // One tag per chunk, with a final full chunk on chunk boundaries.
+            let chunks = if *len == 0 { 1 } else { (len - 1) / CHUNK_SIZE + 1 };
+            assert_eq!(envelope.len(), header_size(1) + len + chunks * TAG_SIZE, "{}", len);
+            assert_eq!(decrypt_with(&envelope, 1).unwrap(), plaintext, "{}", len);
+        }
+    }
+
+    #[test]
+    fn multiple_recipients() {
+        let plaintext = plaintext(CHUNK_SIZE + 10);
+        let envelope = encrypt_to(&plaintext, &[public_key(1), public_key(2), public_key(3)]);
+        assert_eq!(&envelope[..ENVELOPE_MAGIC.len()], ENVELOPE_MAGIC);
+        for seed in 1..=3 {
+            assert_eq!(decrypt_with(&envelope, seed).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn wrong_key() {
+        let envelope = encrypt_to(&plaintext(100), &[public_key(1), public_key(2)]);
+        let e = decrypt_with(&envelope, 3).unwrap_err();
+        assert_eq!(e.to_string(), "Report is not encrypted to this key");
+    }
+
+    #[test]
+    fn truncated() {
+        // Dropping whole chunks, from a zip whose last chunk is full, then from one whose last
+        // chunk is shorter.
+        for len in &[2 * CHUNK_SIZE, 2 * CHUNK_SIZE + 100] {
+            let envelope = encrypt_to(&plaintext(*len), &[public_key(1)]);
+            for chunks in 0..=2 {
+                let end = header_size(1) + chunks * (CHUNK_SIZE + TAG_SIZE);
+                if end < envelope.len() {
+                    assert!(decrypt_with(&envelope[..end], 1).is_err(), "{} {}", len, chunks);
+                }
+            }
+            assert!(decrypt_with(&envelope[..envelope.len() - 1], 1).is_err());
+            assert!(decrypt_with(&envelope[..header_size(1) - 1], 1).is_err());
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let plaintext = plaintext(CHUNK_SIZE + 100);
+        let envelope = encrypt_to(&plaintext, &[public_key(1), public_key(2)]);
+        // Flip a byte in each chunk, in each tag, in the ephemeral key, and in the wrapped key of
+        // the other recipient, which is authenticated as part of the header.
+        let last_chunk = header_size(2) + CHUNK_SIZE + TAG_SIZE;
+        for i in &[
+            header_size(2),
+            header_size(2) + CHUNK_SIZE,
+            last_chunk,
+            envelope.len() - 1,
+            ENVELOPE_MAGIC.len() + 2,
+            header_size(2) - 1,
+        ] {
+            let mut tampered = envelope.clone();
+            tampered[*i] ^= 1;
+            assert!(decrypt_with(&tampered, 1).is_err(), "{}", i);
+        }
+
+        // Swapping chunks.
+        let mut swapped = envelope[..header_size(2)].to_vec();
+        swapped.extend_from_slice(&envelope[last_chunk..]);
+        swapped.extend_from_slice(&envelope[header_size(2)..last_chunk]);
+        assert!(decrypt_with(&swapped, 1).is_err());
+
+        let mut bad_version = envelope;
+        bad_version[ENVELOPE_MAGIC.len()] = 2;
+        assert!(decrypt_with(&bad_version, 1).is_err());
+    }
+
+    #[test]
+    fn no_recipient() {
+        assert!(encrypt(&b"zip"[..], Vec::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn public_keys() {
+        let hex = crate::manifest::to_hex(public_key(1).as_bytes());
+        let keys = parse_public_keys(&format!(" {}, ,{}", hex, hex)).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].as_bytes(), public_key(1).as_bytes());
+        assert!(parse_public_keys("").unwrap().is_empty());
+        assert!(parse_public_keys(&hex[2..]).is_err());
+        assert!(parse_public_keys("not hex").is_err());
+    }
+
+    #[test]
+    fn failed_decryption_leaves_no_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report.zip");
+        let output = dir.path().join("decrypted.zip");
+        std::fs::write(&report, encrypt_to(&plaintext(10), &[public_key(1)])).unwrap();
+
+        let wrong_key = crate::manifest::to_hex(&secret_key(2));
+        assert!(decrypt_report(&report, &output, &wrong_key).is_err());
+        assert!(!output.exists());
+        let key = crate::manifest::to_hex(&secret_key(1));
+        decrypt_report(&report, &output, &key).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), plaintext(10));
+    }
+}
@@ -0,0 +1,168 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Minimal HTTP/1.1 client, only able to POST a file, over plain TCP or TLS.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use std::fs::{read_dir, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::SYSTEM_CA_CERTS_DIR;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// Size of the file chunks sent, between which an upload can be cancelled.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// An "http://" or "https://" URL.
+#[derive(Clone, Debug)]
+pub struct HttpUrl {
+    pub https: bool,
+    /// Host and optional port, as found in the URL.
+    pub authority: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl HttpUrl {
+    /// Parse `url`. The host is only resolved when connecting, see resolve().
+    pub fn parse(url: &str) -> Result<Self> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(anyhow!("Not an HTTP URL: {}", url));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        ensure!(path.chars().all(|c| c.is_ascii_graphic()), "Invalid characters in URL: {}", url);
+
+        // The port follows the last ':' unless it is inside an IPv6 address.
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                (&authority[..i], authority[i + 1..].parse::<u16>().context("Invalid port")?)
+            }
+            _ => (authority, if https { 443 } else { 80 }),
+        };
+        ensure!(!host.is_empty(), "Missing host in URL: {}", url);
+        Ok(HttpUrl {
+            https,
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    /// The host without the brackets around IPv6 addresses.
+    pub fn get_bare_host(&self) -> &str {
+        self.host.trim_start_matches('[').trim_end_matches(']')
+    }
+
+    /// Resolve the host, which may change address over time.
+    pub fn resolve(&self) -> Result<SocketAddr> {
+        (self.get_bare_host(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("Cannot resolve {}", self.host))?
+            .next()
+            .ok_or_else(|| anyhow!("Cannot resolve {}", self.host))
+    }
+}
+
+/// POST the contents of `file` to `url` with the given extra headers. Returns the HTTP status
+/// code. The host is resolved anew on each call. The upload is abandoned, failing, once `cancel`
+/// is set.
+pub fn post_file(
+    url: &HttpUrl,
+    file: &Path,
+    headers: &[(&str, &str)],
+    cancel: &AtomicBool,
+) -> Result<u16> {
+    ensure!(!cancel.load(Ordering::SeqCst), "Upload cancelled");
+    let mut file = File::open(file)?;
+    let len = file.metadata()?.len();
+
+    let stream = TcpStream::connect_timeout(&url.resolve()?, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let mut stream: Box<dyn ReadWrite> = if url.https {
+        let mut config = rustls::ClientConfig::new();
+        config.root_store = load_system_ca_certs()?;
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(&url.host)
+            .map_err(|_| anyhow!("Invalid TLS server name {}", url.host))?;
+        let session = rustls::ClientSession::new(&Arc::new(config), dns_name);
+        Box::new(rustls::StreamOwned::new(session, stream))
+    } else {
+        Box::new(stream)
+    };
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/octet-stream\r\n\
+         Content-Length: {}\r\nConnection: close\r\n",
+        url.path, url.authority, len
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    {
+        let mut writer = io::BufWriter::new(&mut stream);
+        writer.write_all(request.as_bytes())?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            ensure!(!cancel.load(Ordering::SeqCst), "Upload cancelled");
+            let n = file.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            writer.write_all(&chunk[..n])?;
+        }
+        writer.flush()?;
+    }
+
+    let mut status_line = String::new();
+    BufReader::new(&mut stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| anyhow!("Malformed HTTP response: {:?}", status_line.trim_end()))
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+fn load_system_ca_certs() -> Result<rustls::RootCertStore> {
+    let mut store = rustls::RootCertStore::empty();
+    for entry in read_dir(*SYSTEM_CA_CERTS_DIR)? {
+        let path = entry?.path();
+        let mut reader = BufReader::new(File::open(&path)?);
+        if store.add_pem_file(&mut reader).is_err() {
+            log::warn!("Ignoring malformed CA certificate {}", path.display());
+        }
+    }
+    ensure!(!store.is_empty(), "No CA certificate found");
+    Ok(store)
+}
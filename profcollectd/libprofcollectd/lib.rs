@@ -16,14 +16,30 @@
 
 //! ProfCollect Binder client interface.
 
+mod branch_list_merger;
+mod clock;
 mod config;
+mod encryption;
+mod http;
+mod manifest;
+mod policy;
 mod report;
+mod report_index;
+mod report_info;
+mod report_sink;
+mod sample_merger;
 mod scheduler;
 mod service;
+mod signing;
 mod simpleperf_etm_trace_provider;
+mod simpleperf_sampling_trace_provider;
+mod status;
+mod timing;
 mod trace_provider;
+mod trigger;
+mod uploader;
 
-#[cfg(feature = "test")]
+#[cfg(any(test, feature = "test"))]
 mod logging_trace_provider;
 
 use anyhow::{Context, Result};
@@ -33,6 +49,9 @@ use profcollectd_aidl_interface::aidl::com::android::server::profcollect::IProfC
 use profcollectd_aidl_interface::binder::{self, BinderFeatures};
 use service::ProfcollectdBinderService;
 
+pub use encryption::{decrypt, decrypt_report};
+pub use signing::verify_report;
+
 const PROFCOLLECTD_SERVICE_NAME: &str = "profcollectd";
 
 /// Initialise profcollectd service.
@@ -86,9 +105,31 @@ pub fn process() -> Result<()> {
     Ok(())
 }
 
-/// Process traces and report profile.
-pub fn report() -> Result<String> {
-    Ok(get_profcollectd_service()?.report()?)
+/// Refresh the configuration of the running service.
+pub fn reconfig() -> Result<()> {
+    get_profcollectd_service()?.reconfigure()?;
+    Ok(())
+}
+
+/// Process traces and report profiles not reported yet, or all profiles if `full` is set. Returns
+/// the names of the created reports.
+pub fn report(full: bool) -> Result<Vec<String>> {
+    Ok(get_profcollectd_service()?.report(full)?)
+}
+
+/// List the stored reports, as JSON.
+pub fn list_reports() -> Result<String> {
+    Ok(get_profcollectd_service()?.list_reports()?)
+}
+
+/// Describe the contents and manifest of a stored report, as JSON.
+pub fn inspect_report(report_name: &str) -> Result<String> {
+    Ok(get_profcollectd_service()?.inspect_report(report_name)?)
+}
+
+/// Get a human readable summary of the service status.
+pub fn get_status() -> Result<String> {
+    Ok(get_profcollectd_service()?.get_status()?)
 }
 
 /// Clear all local data.
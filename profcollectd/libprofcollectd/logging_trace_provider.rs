@@ -17,6 +17,9 @@
 //! Logging trace provider for development and testing purposes.
 
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use simpleperf_profcollect::BinaryFilter;
+use std::fs::{read_dir, remove_file, File};
 use std::path::Path;
 use std::time::Duration;
 use trace_provider::TraceProvider;
@@ -32,8 +35,15 @@ impl TraceProvider for LoggingTraceProvider {
         "logging"
     }
 
-    fn trace(&self, trace_dir: &Path, tag: &str, sampling_period: &Duration) {
-        let trace_file = trace_provider::get_path(trace_dir, tag, LOGGING_TRACEFILE_EXTENSION);
+    fn trace(
+        &self,
+        trace_dir: &Path,
+        tag: &str,
+        sampling_period: &Duration,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let trace_file =
+            trace_provider::get_path(trace_dir, tag, LOGGING_TRACEFILE_EXTENSION, timestamp);
 
         log::info!(
             "Trace event triggered, tag {}, sampling for {}ms, saving to {}",
@@ -41,11 +51,33 @@ impl TraceProvider for LoggingTraceProvider {
             sampling_period.as_millis(),
             trace_file.display()
         );
+        // Leave an empty trace behind until processed, recording what was traced and when.
+        File::create(&trace_file)?;
+        Ok(())
     }
 
-    fn process(&self, _trace_dir: &Path, _profile_dir: &Path) -> Result<()> {
+    fn process(
+        &self,
+        trace_dir: &Path,
+        _profile_dir: &Path,
+        _binary_filter: &BinaryFilter,
+    ) -> Result<()> {
         log::info!("Process event triggered");
-        Ok(())
+        read_dir(trace_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|e| {
+                e.is_file()
+                    && e.extension()
+                        .and_then(|f| f.to_str())
+                        .filter(|ext| ext == &LOGGING_TRACEFILE_EXTENSION)
+                        .is_some()
+            })
+            .try_for_each(|trace_file| -> Result<()> {
+                log::info!("Processed trace {}", trace_file.display());
+                remove_file(&trace_file)?;
+                Ok(())
+            })
     }
 }
 
@@ -54,3 +86,28 @@ impl LoggingTraceProvider {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn process_removes_traces() {
+        let trace_dir = TempDir::new().unwrap();
+        let profile_dir = TempDir::new().unwrap();
+        let provider = LoggingTraceProvider {};
+        let timestamp = "2021-06-01T12:00:00Z".parse().unwrap();
+        provider.trace(trace_dir.path(), "manual", &Duration::from_millis(500), timestamp).unwrap();
+        // Traces of other providers are left alone.
+        let other = trace_dir.path().join("20210601-120000_manual.etmtrace");
+        write(&other, b"").unwrap();
+
+        provider.process(trace_dir.path(), profile_dir.path(), &BinaryFilter::default()).unwrap();
+        let remaining: Vec<_> =
+            read_dir(trace_dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(remaining, vec![other]);
+        assert_eq!(read_dir(profile_dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Report manifest, describing the provenance of the profiles packed in a report.
+
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::trace_provider;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// Stored as manifest.json in each report.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// Version of manifest scheme, always equals to 1.
+    pub version: u32,
+    /// Configuration the profiles were collected with, including the device node id.
+    pub config: Config,
+    pub trace_provider: String,
+    /// Compression method of the profiles. manifest.json itself is always deflated.
+    pub compression: String,
+    /// RFC 3339 report creation time.
+    pub created: String,
+    /// ID shared by all parts of a report set.
+    pub report_set: String,
+    /// Index of this part in the report set, starting from 1.
+    pub part: u32,
+    /// Number of parts in the report set.
+    pub parts: u32,
+    pub files: Vec<FileEntry>,
+    /// Events that started the traces merged into the profiles of this report set, listed in the
+    /// first part only.
+    #[serde(default)]
+    pub triggers: Vec<TriggerEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileEntry {
+    /// Name of the file in the report.
+    pub name: String,
+    /// Tag of the trace the profile was generated from, None for merged profiles.
+    pub tag: Option<String>,
+    /// RFC 3339 capture time of the trace, None for merged profiles.
+    pub captured: Option<String>,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the file contents.
+    pub sha256: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TriggerEntry {
+    /// Tag of the trace the event started.
+    pub tag: Option<String>,
+    /// RFC 3339 capture time of the trace.
+    pub captured: Option<String>,
+    /// The event, as stored by the trigger module.
+    pub event: serde_json::Value,
+}
+
+impl Manifest {
+    pub fn new(
+        config: &Config,
+        trace_provider: &str,
+        created: DateTime<Utc>,
+        report_set: &str,
+        part: u32,
+        parts: u32,
+    ) -> Self {
+        Manifest {
+            version: 1,
+            config: config.clone(),
+            trace_provider: trace_provider.to_string(),
+            compression: config.report_compression.to_string(),
+            created: created.to_rfc3339(),
+            report_set: report_set.to_string(),
+            part,
+            parts,
+            files: Vec::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, size: u64, sha256: &[u8]) {
+        let (captured, tag) = parse_trace_name(name);
+        self.files.push(FileEntry {
+            name: name.to_string(),
+            tag,
+            captured,
+            size,
+            sha256: to_hex(sha256),
+        });
+    }
+
+    /// Add the trigger event `event`, stored in the file `name`.
+    pub fn add_trigger(&mut self, name: &str, event: serde_json::Value) {
+        let (captured, tag) = parse_trace_name(name);
+        self.triggers.push(TriggerEntry { tag, captured, event });
+    }
+}
+
+/// Capture time and tag of the trace a file is named after, if any.
+fn parse_trace_name(name: &str) -> (Option<String>, Option<String>) {
+    match trace_provider::parse_path(Path::new(name)) {
+        Some((captured, tag)) => (Some(captured.to_rfc3339()), Some(tag)),
+        None => (None, None),
+    }
+}
+
+impl ToString for Manifest {
+    fn to_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialise manifest.")
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    ensure!(hex.len() % 2 == 0 && hex.is_ascii(), "Invalid hex string: {}", hex);
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).context("Invalid hex string"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_entries() {
+        let created = "2021-06-01T12:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut manifest = Manifest::new(&Config::for_test(), "test", created, "set", 2, 3);
+        manifest.add_file("20210601-120000_app_launch.data", 10, &[0xab, 0x01]);
+        manifest.add_file("merged_libc.so_0123456789abcdef.data", 20, &[]);
+
+        let manifest: Manifest = serde_json::from_str(&manifest.to_string()).unwrap();
+        assert_eq!(manifest.version, 1);
+        assert_eq!(manifest.created, "2021-06-01T12:30:00+00:00");
+        assert_eq!((manifest.report_set.as_str(), manifest.part, manifest.parts), ("set", 2, 3));
+        let trace = &manifest.files[0];
+        assert_eq!(trace.tag.as_deref(), Some("app_launch"));
+        assert_eq!(trace.captured.as_deref(), Some("2021-06-01T12:00:00+00:00"));
+        assert_eq!((trace.size, trace.sha256.as_str()), (10, "ab01"));
+        let merged = &manifest.files[1];
+        assert_eq!((merged.tag.as_ref(), merged.captured.as_ref()), (None, None));
+        assert_eq!((merged.size, merged.sha256.as_str()), (20, ""));
+    }
+
+    #[test]
+    fn trigger_entries() {
+        let created = "2021-06-01T12:30:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut manifest = Manifest::new(&Config::for_test(), "test", created, "set", 1, 1);
+        manifest.add_trigger("20210601-120000_psi_io.trigger", serde_json::json!({"avg10": 1.5}));
+
+        let mut json: serde_json::Value = serde_json::from_str(&manifest.to_string()).unwrap();
+        let trigger = &json["triggers"][0];
+        assert_eq!(trigger["tag"], "psi_io");
+        assert_eq!(trigger["captured"], "2021-06-01T12:00:00+00:00");
+        assert_eq!(trigger["event"]["avg10"], 1.5);
+
+        // Manifests from before triggers were listed are still readable.
+        json.as_object_mut().unwrap().remove("triggers");
+        let manifest: Manifest = serde_json::from_value(json).unwrap();
+        assert!(manifest.triggers.is_empty());
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xff]), "007fff");
+        assert_eq!(from_hex("007fFF").unwrap(), vec![0x00, 0x7f, 0xff]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("é0").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Device state conditions a trace is subject to, read from sysfs and procfs.
+
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+/// Why a trace was skipped.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SkipReason {
+    NotCharging,
+    BatteryLow,
+    Thermal,
+    CpuPressure,
+    LoadAverage,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::NotCharging => write!(f, "not_charging"),
+            SkipReason::BatteryLow => write!(f, "battery_low"),
+            SkipReason::Thermal => write!(f, "thermal"),
+            SkipReason::CpuPressure => write!(f, "cpu_pressure"),
+            SkipReason::LoadAverage => write!(f, "load_average"),
+        }
+    }
+}
+
+/// Thresholds from the config, checked against the files under `root`, which is "/" on the
+/// device.
+pub struct CollectionPolicy {
+    root: PathBuf,
+    require_charging: bool,
+    min_battery_capacity: u32,
+    max_thermal_temp: u32,
+    max_cpu_pressure: u32,
+    max_load_average: u32,
+}
+
+impl CollectionPolicy {
+    pub fn new(config: &Config, root: &Path) -> Self {
+        CollectionPolicy {
+            root: root.to_path_buf(),
+            require_charging: config.require_charging,
+            min_battery_capacity: config.min_battery_capacity,
+            max_thermal_temp: config.max_thermal_temp,
+            max_cpu_pressure: config.max_cpu_pressure,
+            max_load_average: config.max_load_average,
+        }
+    }
+
+    /// Lift the CPU pressure and load average limits, for traces meant to capture high load.
+    pub fn without_load_limits(mut self) -> Self {
+        self.max_cpu_pressure = 0;
+        self.max_load_average = 0;
+        self
+    }
+
+    /// Returns why tracing is not allowed right now, if it isn't. Conditions that cannot be read
+    /// are ignored, e.g. the battery on devices without one, or PSI on kernels without it.
+    pub fn check(&self) -> Option<SkipReason> {
+        if self.require_charging || self.min_battery_capacity > 0 {
+            match self.read_battery() {
+                Ok((charging, _)) if self.require_charging && !charging => {
+                    return Some(SkipReason::NotCharging)
+                }
+                Ok((_, capacity)) if capacity < self.min_battery_capacity => {
+                    return Some(SkipReason::BatteryLow)
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Cannot read battery state: {:#}", e),
+            }
+        }
+        if self.max_thermal_temp > 0 {
+            match self.read_max_thermal_temp() {
+                Ok(temp) if temp > self.max_thermal_temp as f64 => {
+                    return Some(SkipReason::Thermal)
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Cannot read thermal zones: {:#}", e),
+            }
+        }
+        if self.max_cpu_pressure > 0 {
+            match self.read_cpu_pressure() {
+                Ok(pressure) if pressure > self.max_cpu_pressure as f64 => {
+                    return Some(SkipReason::CpuPressure)
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Cannot read CPU pressure: {:#}", e),
+            }
+        }
+        if self.max_load_average > 0 {
+            match self.read_load_average() {
+                Ok(load) if load > self.max_load_average as f64 => {
+                    return Some(SkipReason::LoadAverage)
+                }
+                Ok(_) => (),
+                Err(e) => log::warn!("Cannot read load average: {:#}", e),
+            }
+        }
+        None
+    }
+
+    /// Read the trimmed contents of `path`, relative to the root.
+    fn read(&self, path: impl AsRef<Path>) -> Result<String> {
+        let path = self.root.join(path);
+        Ok(read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .trim()
+            .to_string())
+    }
+
+    /// Whether the battery is charging or full, and its capacity in percent.
+    fn read_battery(&self) -> Result<(bool, u32)> {
+        for entry in read_dir(self.root.join("sys/class/power_supply"))? {
+            let supply = Path::new("sys/class/power_supply").join(entry?.file_name());
+            let read = |name: &str| self.read(supply.join(name));
+            if read("type").ok().as_deref() != Some("Battery") {
+                continue;
+            }
+            let charging = matches!(read("status")?.as_str(), "Charging" | "Full");
+            return Ok((charging, read("capacity")?.parse()?));
+        }
+        Err(anyhow!("No battery found"))
+    }
+
+    /// Highest temperature of all thermal zones, in degrees Celsius.
+    fn read_max_thermal_temp(&self) -> Result<f64> {
+        let mut max_temp = None;
+        for entry in read_dir(self.root.join("sys/class/thermal"))? {
+            let zone = entry?.file_name();
+            if !zone.to_string_lossy().starts_with("thermal_zone") {
+                continue;
+            }
+            let temp = Path::new("sys/class/thermal").join(zone).join("temp");
+            // Disabled zones fail to read.
+            if let Ok(millidegrees) = self.read(temp) {
+                let temp = millidegrees.parse::<i64>()? as f64 / 1000.0;
+                max_temp = Some(max_temp.map_or(temp, |max: f64| max.max(temp)));
+            }
+        }
+        max_temp.ok_or_else(|| anyhow!("No thermal zone found"))
+    }
+
+    /// Share of time in percent some tasks were stalled on CPU, over the last 10 seconds.
+    fn read_cpu_pressure(&self) -> Result<f64> {
+        parse_psi_avg10(&self.read("proc/pressure/cpu")?)
+    }
+
+    /// Load average over the last minute.
+    fn read_load_average(&self) -> Result<f64> {
+        let loadavg = self.read("proc/loadavg")?;
+        let load = loadavg.split_whitespace().next().ok_or_else(|| anyhow!("Empty loadavg"))?;
+        Ok(load.parse()?)
+    }
+}
+
+/// Parse the "some avg10" value of a PSI file, such as /proc/pressure/cpu:
+/// "some avg10=1.23 avg60=0.50 avg300=0.10 total=12345".
+pub fn parse_psi_avg10(psi: &str) -> Result<f64> {
+    psi.lines()
+        .find(|line| line.starts_with("some "))
+        .and_then(|line| line.split_whitespace().find_map(|f| f.strip_prefix("avg10=")))
+        .ok_or_else(|| anyhow!("Malformed PSI: {}", psi))?
+        .parse()
+        .context("Malformed PSI")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn write(root: &TempDir, path: &str, contents: &str) {
+        let path = root.path().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// A device with a discharging battery at 50%, a thermal zone at 40°C and an idle CPU.
+    fn fake_device() -> TempDir {
+        let root = TempDir::new().unwrap();
+        write(&root, "sys/class/power_supply/usb/type", "USB\n");
+        write(&root, "sys/class/power_supply/battery/type", "Battery\n");
+        write(&root, "sys/class/power_supply/battery/status", "Discharging\n");
+        write(&root, "sys/class/power_supply/battery/capacity", "50\n");
+        write(&root, "sys/class/thermal/thermal_zone0/temp", "40000\n");
+        write(&root, "sys/class/thermal/cooling_device0/cur_state", "0\n");
+        write(
+            &root,
+            "proc/pressure/cpu",
+            "some avg10=1.50 avg60=1.00 avg300=0.50 total=1000\n\
+             full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+        );
+        write(&root, "proc/loadavg", "2.50 2.00 1.50 3/1000 12345\n");
+        root
+    }
+
+    fn check(root: &TempDir, update: impl FnOnce(&mut Config)) -> Option<SkipReason> {
+        let mut config = Config::for_test();
+        update(&mut config);
+        CollectionPolicy::new(&config, root.path()).check()
+    }
+
+    #[test]
+    fn allows_by_default() {
+        let root = fake_device();
+        assert_eq!(check(&root, |_| ()), None);
+        assert_eq!(check(&TempDir::new().unwrap(), |_| ()), None);
+    }
+
+    #[test]
+    fn battery() {
+        let root = fake_device();
+        assert_eq!(check(&root, |c| c.require_charging = true), Some(SkipReason::NotCharging));
+        assert_eq!(check(&root, |c| c.min_battery_capacity = 60), Some(SkipReason::BatteryLow));
+        assert_eq!(check(&root, |c| c.min_battery_capacity = 50), None);
+        write(&root, "sys/class/power_supply/battery/status", "Full\n");
+        assert_eq!(check(&root, |c| c.require_charging = true), None);
+    }
+
+    #[test]
+    fn thermal() {
+        let root = fake_device();
+        assert_eq!(check(&root, |c| c.max_thermal_temp = 40), None);
+        write(&root, "sys/class/thermal/thermal_zone1/temp", "45500\n");
+        assert_eq!(check(&root, |c| c.max_thermal_temp = 45), Some(SkipReason::Thermal));
+    }
+
+    #[test]
+    fn cpu_pressure_and_load() {
+        let root = fake_device();
+        assert_eq!(check(&root, |c| c.max_cpu_pressure = 1), Some(SkipReason::CpuPressure));
+        assert_eq!(check(&root, |c| c.max_cpu_pressure = 2), None);
+        assert_eq!(check(&root, |c| c.max_load_average = 2), Some(SkipReason::LoadAverage));
+        assert_eq!(check(&root, |c| c.max_load_average = 3), None);
+    }
+
+    #[test]
+    fn unreadable_conditions_are_ignored() {
+        let root = TempDir::new().unwrap();
+        write(&root, "sys/class/power_supply/usb/type", "USB\n");
+        write(&root, "proc/pressure/cpu", "garbage\n");
+        assert_eq!(
+            check(&root, |c| {
+                c.require_charging = true;
+                c.max_thermal_temp = 1;
+                c.max_cpu_pressure = 1;
+                c.max_load_average = 1;
+            }),
+            None
+        );
+    }
+}
@@ -16,78 +16,825 @@
 
 //! Pack profiles into reports.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as _, Result};
+use ed25519_dalek::Keypair;
 use lazy_static::lazy_static;
 use macaddr::MacAddr6;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File, Permissions};
-use std::io::{Read, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use uuid::v1::{Context, Timestamp};
-use uuid::Uuid;
+use uuid::{Builder, Uuid, Variant, Version};
+use x25519_dalek::PublicKey;
 use zip::write::FileOptions;
-use zip::CompressionMethod::Deflated;
+use zip::CompressionMethod;
 use zip::ZipWriter;
 
-use crate::config::Config;
+use crate::branch_list_merger::MERGED_PROFILE_PREFIX;
+use crate::clock::Clock;
+use crate::config::{Config, ReportCompression, ReportNaming, CONFIG_FILE};
+use crate::encryption::{encrypt, parse_public_keys};
+use crate::manifest::{Manifest, MANIFEST_FILENAME};
+use crate::report_index::{ProfileVersion, ReportIndex};
+use crate::signing::{ReportSignature, SIGNATURE_FILENAME};
+use crate::trigger::TRIGGER_EXTENSION;
 
 lazy_static! {
     pub static ref UUID_CONTEXT: Context = Context::new(0);
 }
 
-pub fn pack_report(profile: &Path, report: &Path, config: &Config) -> Result<String> {
-    let mut report = PathBuf::from(report);
-    let report_filename = get_report_filename(&config.node_id)?;
-    report.push(&report_filename);
+/// Extension of reports being written. Incomplete reports left behind by a crash are removed on
+/// service start.
+pub const TMP_REPORT_EXTENSION: &str = "tmp";
+
+/// Extension of the plaintext zip of a report being encrypted.
+const PLAIN_TMP_REPORT_EXTENSION: &str = "plain.tmp";
+
+/// Size of the buffer used to copy profiles into the report.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Space reserved in each part for the manifest header and the zip end of central directory.
+const PART_OVERHEAD: u64 = 16 * 1024;
+
+/// Space reserved for each file for its zip headers and its manifest entry.
+const FILE_OVERHEAD: u64 = 1024;
+
+/// Pack profiles into one or more reports, each no larger than `config.max_report_size` bytes, or
+/// unbounded if it is 0. Returns the report names, which share the report set id,
+/// or nothing if there is no profile to report.
+///
+/// Unless `full` is set, only profiles not yet in `index` are packed. Packed profiles are added to
+/// `index`, and merged profiles are sealed, see seal_merged_profile(). The config file is included
+/// in every report set, but never indexed.
+///
+/// Trigger events are not packed, but listed in the manifest of the first part, since the traces
+/// they started were merged into the profiles of the report set. They are added to `index` under
+/// that part, and left for the next report set if there is no profile to report.
+///
+/// Reports are encrypted if `config.report_public_keys` is set, see the encryption module for the
+/// format. Nothing is reported if the keys are invalid. The manifest of each report is signed with
+/// `signing_key`.
+///
+/// A profile larger than the maximum report size on its own is still packed, alone in its part.
+///
+/// The caller must keep traces from being processed until this returns, as counts merged into a
+/// profile between packing and sealing it would be reported twice.
+#[allow(clippy::too_many_arguments)]
+pub fn pack_report(
+    profile: &Path,
+    report: &Path,
+    config: &Config,
+    trace_provider: &str,
+    signing_key: &Keypair,
+    clock: &dyn Clock,
+    index: &mut ReportIndex,
+    full: bool,
+) -> Result<Vec<String>> {
+    let recipients = parse_public_keys(&config.report_public_keys)
+        .context("Invalid report_public_keys, not reporting")?;
+    index.retain_profiles(profile);
+    let parts =
+        split_profiles(profile, config.max_report_size, config.report_compression, index, full)?;
+    if parts.is_empty() {
+        log::info!("No new profiles to report");
+        return Ok(Vec::new());
+    }
+    let triggers = read_triggers(profile, index, full)?;
+    let created = clock.now();
+    let report_set =
+        get_report_set_id(config.report_naming, &config.node_id, SystemTime::from(created))?;
+
+    let mut report_names: Vec<String> = Vec::new();
+    for (i, profiles) in parts.iter().enumerate() {
+        let part = i as u32 + 1;
+        let report_name = format!("{}-{}", report_set, part);
+        let mut manifest =
+            Manifest::new(config, trace_provider, created, &report_set, part, parts.len() as u32);
+        if part == 1 {
+            for (name, event, _) in &triggers {
+                manifest.add_trigger(name, event.clone());
+            }
+        }
+        let report_path = get_report_path(report, &report_name);
+        let result = pack_part(
+            profiles,
+            &report_path,
+            config.report_compression,
+            &recipients,
+            manifest,
+            signing_key,
+        );
+        if let Err(e) = result {
+            // Don't leave an incomplete report set behind.
+            for report_name in &report_names {
+                fs::remove_file(get_report_path(report, report_name)).ok();
+            }
+            return Err(e);
+        }
+        report_names.push(report_name);
+    }
+
+    for (profiles, report_name) in parts.iter().zip(&report_names) {
+        for (profile, version) in profiles {
+            if !is_config_file(profile) {
+                let profile = seal_merged_profile(profile, report_name);
+                index.mark_reported(&profile, *version, report_name);
+            }
+        }
+    }
+    for (name, _, version) in triggers {
+        index.mark_reported(&profile.join(name), version, &report_names[0]);
+    }
+    Ok(report_names)
+}
+
+fn is_trigger_file(profile: &Path) -> bool {
+    profile.extension().and_then(|e| e.to_str()) == Some(TRIGGER_EXTENSION)
+}
+
+/// The trigger events to report, with their file names and versions, oldest first.
+fn read_triggers(
+    profile: &Path,
+    index: &ReportIndex,
+    full: bool,
+) -> Result<Vec<(String, serde_json::Value, ProfileVersion)>> {
+    let mut triggers = Vec::new();
+    for trigger in fs::read_dir(profile)?.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if !trigger.is_file() || !is_trigger_file(&trigger) {
+            continue;
+        }
+        let version = ProfileVersion::new(&trigger)?;
+        if !full && index.is_reported(&trigger, &version) {
+            continue;
+        }
+        let name = match trigger.file_name().and_then(|f| f.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // Possibly still being written, retried on the next report.
+        match fs::read_to_string(&trigger)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str(&json)?))
+        {
+            Ok(event) => triggers.push((name, event, version)),
+            Err(e) => log::warn!("Cannot read trigger event {}: {:#}", trigger.display(), e),
+        }
+    }
+    triggers.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(triggers)
+}
+
+/// Rename a merged profile after the report it was packed into, so that later traces are merged
+/// into a new generation of the profile instead of adding to counts that were already reported.
+/// The sealed profile is then deleted like any other once the report is delivered. Returns the
+/// new path of the profile, unchanged for other profiles.
+fn seal_merged_profile(profile: &Path, report_name: &str) -> PathBuf {
+    let name = match profile.file_name().and_then(|f| f.to_str()) {
+        Some(name) if name.starts_with(MERGED_PROFILE_PREFIX) => name,
+        _ => return profile.to_path_buf(),
+    };
+    let sealed = profile.with_file_name(format!("{}.{}", report_name, name));
+    match fs::rename(profile, &sealed) {
+        Ok(()) => sealed,
+        Err(e) => {
+            log::error!("Failed to seal merged profile {}: {}", profile.display(), e);
+            profile.to_path_buf()
+        }
+    }
+}
+
+pub fn get_report_path(report_dir: &Path, report_name: &str) -> PathBuf {
+    let mut report = PathBuf::from(report_dir);
+    report.push(report_name);
     report.set_extension("zip");
+    report
+}
+
+fn is_config_file(profile: &Path) -> bool {
+    profile.file_name() == CONFIG_FILE.file_name()
+}
+
+/// Group the profiles to report so that each group fits in a report of `max_report_size` bytes,
+/// assuming the worst case compression ratio.
+fn split_profiles(
+    profile: &Path,
+    max_report_size: u64,
+    compression: ReportCompression,
+    index: &ReportIndex,
+    full: bool,
+) -> Result<Vec<Vec<(PathBuf, ProfileVersion)>>> {
+    let mut config_file = None;
+    let mut profiles = Vec::new();
+    for profile in fs::read_dir(profile)?.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if !profile.is_file() {
+            continue;
+        }
+        if is_trigger_file(&profile) {
+            continue;
+        }
+        let version = ProfileVersion::new(&profile)?;
+        if is_config_file(&profile) {
+            config_file = Some((profile, version));
+        } else if full || !index.is_reported(&profile, &version) {
+            profiles.push((profile, version));
+        }
+    }
+    if profiles.is_empty() {
+        return Ok(Vec::new());
+    }
+    profiles.sort_by(|a, b| a.0.cmp(&b.0));
+    profiles.splice(0..0, config_file);
+
+    let mut parts = vec![Vec::new()];
+    let mut part_size = PART_OVERHEAD;
+    for (profile, version) in profiles {
+        let size = compress_bound(compression, version.size) + FILE_OVERHEAD;
+        if max_report_size > 0 {
+            if PART_OVERHEAD + size > max_report_size {
+                log::warn!(
+                    "Profile {} exceeds the maximum report size {}",
+                    profile.display(),
+                    max_report_size
+                );
+            }
+            let current = parts.last().unwrap();
+            if !current.is_empty() && part_size + size > max_report_size {
+                parts.push(Vec::new());
+                part_size = PART_OVERHEAD;
+            }
+        }
+        part_size += size;
+        parts.last_mut().unwrap().push((profile, version));
+    }
+    Ok(parts)
+}
+
+/// Upper bound of the compressed size of `size` bytes, same as zlib's compressBound().
+fn compress_bound(compression: ReportCompression, size: u64) -> u64 {
+    match compression {
+        ReportCompression::Stored => size,
+        ReportCompression::Deflate => size + (size >> 12) + (size >> 14) + (size >> 25) + 13,
+    }
+}
+
+fn get_file_options(compression: ReportCompression) -> FileOptions {
+    let method = match compression {
+        ReportCompression::Stored => CompressionMethod::Stored,
+        ReportCompression::Deflate => CompressionMethod::Deflated,
+    };
+    FileOptions::default().compression_method(method)
+}
+
+fn pack_part(
+    profiles: &[(PathBuf, ProfileVersion)],
+    report: &Path,
+    compression: ReportCompression,
+    recipients: &[PublicKey],
+    manifest: Manifest,
+    signing_key: &Keypair,
+) -> Result<()> {
+    let tmp_report = report.with_extension(TMP_REPORT_EXTENSION);
+    let plain_report = report.with_extension(PLAIN_TMP_REPORT_EXTENSION);
 
     // Remove the current report file if exists.
-    fs::remove_file(&report).ok();
+    fs::remove_file(report).ok();
+    fs::remove_file(&tmp_report).ok();
+    fs::remove_file(&plain_report).ok();
 
-    let report_file = fs::OpenOptions::new().create_new(true).write(true).open(&report)?;
+    // Write to a temporary file first, so that a crash or a full disk never leaves a truncated
+    // report behind for uploaders.
+    let result = if recipients.is_empty() {
+        write_report(profiles, &tmp_report, compression, manifest, signing_key)
+    } else {
+        write_report(profiles, &plain_report, compression, manifest, signing_key)
+            .and_then(|_| encrypt_report(&plain_report, &tmp_report, recipients))
+    };
+    fs::remove_file(&plain_report).ok();
 
     // Set report file ACL bits to 644, so that this can be shared to uploaders.
     // Who has permission to actually read the file is protected by SELinux policy.
-    fs::set_permissions(&report, Permissions::from_mode(0o644))?;
+    let result = result
+        .and_then(|_| Ok(fs::set_permissions(&tmp_report, Permissions::from_mode(0o644))?))
+        .and_then(|_| Ok(fs::rename(&tmp_report, report)?));
+    if result.is_err() {
+        fs::remove_file(&tmp_report).ok();
+    }
+    result
+}
 
-    let options = FileOptions::default().compression_method(Deflated);
+fn encrypt_report(plain_report: &Path, report: &Path, recipients: &[PublicKey]) -> Result<()> {
+    let report_file =
+        fs::OpenOptions::new().create_new(true).write(true).mode(0o600).open(report)?;
+    encrypt(File::open(plain_report)?, &report_file, recipients)?;
+    report_file.sync_all()?;
+    Ok(())
+}
+
+fn write_report(
+    profiles: &[(PathBuf, ProfileVersion)],
+    report: &Path,
+    compression: ReportCompression,
+    mut manifest: Manifest,
+    signing_key: &Keypair,
+) -> Result<()> {
+    // Only readable by profcollectd until complete, or at all if the report is to be encrypted.
+    let report_file =
+        fs::OpenOptions::new().create_new(true).write(true).mode(0o600).open(report)?;
+
+    let options = get_file_options(compression);
     let mut zip = ZipWriter::new(report_file);
+    let mut buffer = vec![0u8; COPY_BUFFER_SIZE];
+
+    profiles.iter().try_for_each(|(e, _)| -> Result<()> {
+        let filename = e
+            .file_name()
+            .and_then(|f| f.to_str())
+            .ok_or_else(|| anyhow!("Malformed profile path: {}", e.display()))?;
+        zip.start_file(filename, options)?;
+        let mut f = File::open(e)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            let len = match f.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            zip.write_all(&buffer[..len])?;
+            hasher.update(&buffer[..len]);
+            size += len as u64;
+        }
+        manifest.add_file(filename, size, &hasher.finalize());
+        Ok(())
+    })?;
+    // Keep the manifest and its signature readable by any zip tool, whatever the profiles are
+    // compressed with.
+    let manifest = manifest.to_string();
+    zip.start_file(MANIFEST_FILENAME, get_file_options(ReportCompression::default()))?;
+    zip.write_all(manifest.as_bytes())?;
+    zip.start_file(SIGNATURE_FILENAME, get_file_options(ReportCompression::default()))?;
+    zip.write_all(ReportSignature::new(signing_key, manifest.as_bytes()).to_string().as_bytes())?;
+    zip.finish()?.sync_all()?;
+    Ok(())
+}
 
-    fs::read_dir(profile)?
-        .filter_map(|e| e.ok())
-        .map(|e| e.path())
-        .filter(|e| e.is_file())
-        .try_for_each(|e| -> Result<()> {
-            let filename = e
-                .file_name()
-                .and_then(|f| f.to_str())
-                .ok_or_else(|| anyhow!("Malformed profile path: {}", e.display()))?;
-            zip.start_file(filename, options)?;
-            let mut f = File::open(e)?;
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer)?;
-            zip.write_all(&*buffer)?;
-            Ok(())
-        })?;
-    zip.finish()?;
-
-    Ok(report_filename)
-}
-
-fn get_report_filename(node_id: &MacAddr6) -> Result<String> {
-    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    let ts =
-        Timestamp::from_unix(&*UUID_CONTEXT, since_epoch.as_secs(), since_epoch.subsec_nanos());
-    let uuid = Uuid::new_v1(ts, &node_id.as_bytes())?;
+fn get_report_set_id(
+    naming: ReportNaming,
+    node_id: &MacAddr6,
+    created: SystemTime,
+) -> Result<String> {
+    let since_epoch = created.duration_since(SystemTime::UNIX_EPOCH)?;
+    let uuid = match naming {
+        ReportNaming::Uuid1 => {
+            let ts = Timestamp::from_unix(
+                &*UUID_CONTEXT,
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos(),
+            );
+            Uuid::new_v1(ts, &node_id.as_bytes())?
+        }
+        ReportNaming::Uuid4 => {
+            let mut bytes = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            Builder::from_bytes(bytes)
+                .set_variant(Variant::RFC4122)
+                .set_version(Version::Random)
+                .build()
+        }
+        ReportNaming::Uuid7 => {
+            // 48 bits of milliseconds since epoch, then random bits around the version and
+            // variant fields.
+            let mut bytes = [0u8; 16];
+            rand::thread_rng().fill_bytes(&mut bytes[6..]);
+            bytes[..6].copy_from_slice(&(since_epoch.as_millis() as u64).to_be_bytes()[2..]);
+            bytes[6] = (bytes[6] & 0x0f) | 0x70;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+            Uuid::from_bytes(bytes)
+        }
+    };
     Ok(uuid.to_string())
 }
 
-/// Get report creation timestamp through its filename (version 1 UUID).
-pub fn get_report_ts(filename: &str) -> Result<SystemTime> {
-    let uuid_ts = Uuid::parse_str(filename)?
-        .to_timestamp()
-        .ok_or_else(|| anyhow!("filename is not a valid V1 UUID."))?
-        .to_unix();
-    Ok(SystemTime::UNIX_EPOCH + Duration::new(uuid_ts.0, uuid_ts.1))
+/// Get report creation timestamp. The report name is a report set id optionally followed by a
+/// part number. Version 1 and 7 UUIDs hold the creation time, for other versions the report file
+/// modification time is used, as reports are never modified once written.
+pub fn get_report_ts(report: &Path) -> Result<SystemTime> {
+    let filename = report
+        .file_stem()
+        .and_then(|f| f.to_str())
+        .ok_or_else(|| anyhow!("Malformed path {}", report.display()))?;
+    let uuid = Uuid::parse_str(filename.get(..36).unwrap_or(filename))?;
+    match uuid.get_version_num() {
+        1 => {
+            let uuid_ts = uuid
+                .to_timestamp()
+                .ok_or_else(|| anyhow!("filename is not a valid V1 UUID."))?
+                .to_unix();
+            Ok(SystemTime::UNIX_EPOCH + Duration::new(uuid_ts.0, uuid_ts.1))
+        }
+        7 => {
+            let mut millis = [0u8; 8];
+            millis[2..].copy_from_slice(&uuid.as_bytes()[..6]);
+            Ok(SystemTime::UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)))
+        }
+        _ => Ok(report.metadata()?.modified()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::manifest::to_hex;
+    use chrono::{DateTime, Utc};
+    use ed25519_dalek::SecretKey;
+    use tempfile::TempDir;
+    use zip::ZipArchive;
+
+    const TRACE_PROVIDER: &str = "test_provider";
+
+    fn created() -> DateTime<Utc> {
+        "2021-06-01T12:30:00Z".parse().unwrap()
+    }
+
+    fn signing_key() -> Keypair {
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        Keypair { public: ed25519_dalek::PublicKey::from(&secret), secret }
+    }
+
+    fn pack(
+        profile_dir: &Path,
+        report_dir: &Path,
+        config: &Config,
+        index: &mut ReportIndex,
+    ) -> Result<Vec<String>> {
+        let clock = VirtualClock::new(created());
+        pack_report(
+            profile_dir,
+            report_dir,
+            config,
+            TRACE_PROVIDER,
+            &signing_key(),
+            &clock,
+            index,
+            false,
+        )
+    }
+
+    fn read_manifest(report: &Path) -> Manifest {
+        let mut zip = ZipArchive::new(File::open(report).unwrap()).unwrap();
+        let manifest = zip.by_name(MANIFEST_FILENAME).unwrap();
+        serde_json::from_reader(manifest).unwrap()
+    }
+
+    #[test]
+    fn manifest_describes_profiles() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        let mut config = Config::for_test();
+        config.node_id = MacAddr6::new(2, 0, 0, 0, 0, 1);
+        config.binary_filter = "^/system/".to_string();
+        let profiles = [
+            ("config.json", config.to_string().into_bytes()),
+            ("20210601-120000_periodic.data", b"periodic".to_vec()),
+            ("merged_libc.so_0123456789abcdef.data", b"merged".to_vec()),
+        ];
+        for (name, contents) in &profiles {
+            fs::write(profile_dir.path().join(name), contents).unwrap();
+        }
+
+        let names =
+            pack(profile_dir.path(), report_dir.path(), &config, &mut ReportIndex::default())
+                .unwrap();
+        assert_eq!(names.len(), 1);
+        let manifest = read_manifest(&get_report_path(report_dir.path(), &names[0]));
+        assert_eq!(manifest.version, 1);
+        assert_eq!(manifest.config.node_id, config.node_id);
+        assert_eq!(manifest.config.build_fingerprint, config.build_fingerprint);
+        assert_eq!(manifest.config.binary_filter, config.binary_filter);
+        assert_eq!(manifest.trace_provider, TRACE_PROVIDER);
+        assert_eq!(manifest.compression, config.report_compression.to_string());
+        assert_eq!(manifest.created, created().to_rfc3339());
+        assert_eq!(format!("{}-1", manifest.report_set), names[0]);
+        assert_eq!((manifest.part, manifest.parts), (1, 1));
+
+        // The config comes first, then the profiles by name.
+        assert_eq!(manifest.files.len(), profiles.len());
+        for (entry, (name, contents)) in manifest.files.iter().zip(&profiles) {
+            assert_eq!(entry.name, *name);
+            assert_eq!(entry.size, contents.len() as u64);
+            assert_eq!(entry.sha256, to_hex(&Sha256::digest(contents)));
+        }
+        let trace = &manifest.files[1];
+        assert_eq!(trace.tag.as_deref(), Some("periodic"));
+        assert_eq!(trace.captured.as_deref(), Some("2021-06-01T12:00:00+00:00"));
+        for entry in &[&manifest.files[0], &manifest.files[2]] {
+            assert_eq!((entry.tag.as_ref(), entry.captured.as_ref()), (None, None));
+        }
+    }
+
+    /// Files left in `dir`, by name.
+    fn list_dir(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Add a profile that is listed as a regular file but fails to read: /proc/self/mem of the
+    /// test process cannot be read at address 0.
+    fn add_unreadable_profile(profile_dir: &Path, name: &str) {
+        std::os::unix::fs::symlink("/proc/self/mem", profile_dir.join(name)).unwrap();
+    }
+
+    #[test]
+    fn failed_report_leaves_nothing() {
+        let recipient = PublicKey::from(&x25519_dalek::StaticSecret::from([1; 32]));
+        for public_keys in &["", &to_hex(recipient.as_bytes())] {
+            let profile_dir = TempDir::new().unwrap();
+            let report_dir = TempDir::new().unwrap();
+            let mut config = Config::for_test();
+            config.report_public_keys = public_keys.to_string();
+            // One profile per part, so that the first part is complete when the second fails.
+            config.max_report_size = PART_OVERHEAD + 2 * FILE_OVERHEAD;
+            fs::write(profile_dir.path().join("a.data"), b"profile").unwrap();
+            add_unreadable_profile(profile_dir.path(), "b.data");
+
+            let mut index = ReportIndex::default();
+            assert!(pack(profile_dir.path(), report_dir.path(), &config, &mut index).is_err());
+            assert!(list_dir(report_dir.path()).is_empty());
+            // Nothing was reported, everything is packed again next time.
+            for name in &["a.data", "b.data"] {
+                let profile = profile_dir.path().join(name);
+                assert!(!index.is_reported(&profile, &ProfileVersion::new(&profile).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn report_replaced_atomically() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        fs::write(profile_dir.path().join("a.data"), vec![1u8; 3 * COPY_BUFFER_SIZE + 1]).unwrap();
+        let manifest = Manifest::new(&Config::for_test(), TRACE_PROVIDER, created(), "set", 1, 1);
+        let profiles = vec![(
+            profile_dir.path().join("a.data"),
+            ProfileVersion::new(&profile_dir.path().join("a.data")).unwrap(),
+        )];
+        let report = get_report_path(report_dir.path(), "set-1");
+        // Leftovers of an interrupted attempt.
+        fs::write(&report, b"truncated").unwrap();
+        fs::write(report.with_extension(TMP_REPORT_EXTENSION), b"truncated").unwrap();
+
+        pack_part(&profiles, &report, ReportCompression::default(), &[], manifest, &signing_key())
+            .unwrap();
+        assert_eq!(list_dir(report_dir.path()), vec!["set-1.zip"]);
+        assert_eq!(report.metadata().unwrap().permissions().mode() & 0o777, 0o644);
+        let mut zip = ZipArchive::new(File::open(&report).unwrap()).unwrap();
+        assert_eq!(zip.by_name("a.data").unwrap().size(), 3 * COPY_BUFFER_SIZE as u64 + 1);
+
+        // A report that cannot be moved into place is dropped.
+        let manifest = Manifest::new(&Config::for_test(), TRACE_PROVIDER, created(), "set", 2, 2);
+        let report = get_report_path(report_dir.path(), "set-2");
+        fs::create_dir(&report).unwrap();
+        fs::write(report.join("file"), b"").unwrap();
+        assert!(pack_part(
+            &profiles,
+            &report,
+            ReportCompression::default(),
+            &[],
+            manifest,
+            &signing_key()
+        )
+        .is_err());
+        assert_eq!(list_dir(report_dir.path()), vec!["set-1.zip", "set-2.zip"]);
+        assert!(report.is_dir());
+    }
+
+    fn names(parts: &[Vec<(PathBuf, ProfileVersion)>]) -> Vec<Vec<String>> {
+        parts
+            .iter()
+            .map(|part| {
+                part.iter()
+                    .map(|(p, _)| p.file_name().unwrap().to_str().unwrap().to_string())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn profiles_split_by_size() {
+        let profile_dir = TempDir::new().unwrap();
+        let max_report_size = PART_OVERHEAD + 2 * (1000 + FILE_OVERHEAD);
+        for (name, size) in &[
+            ("config.json", 100),
+            ("a.data", 1000),
+            ("b.data", 1000),
+            ("c.data", 1000),
+            ("d.data", max_report_size),
+        ] {
+            fs::write(profile_dir.path().join(name), vec![0u8; *size as usize]).unwrap();
+        }
+        let index = ReportIndex::default();
+
+        let parts = split_profiles(
+            profile_dir.path(),
+            max_report_size,
+            ReportCompression::Stored,
+            &index,
+            false,
+        )
+        .unwrap();
+        // The config file comes first, and the oversized profile gets a part of its own.
+        assert_eq!(
+            names(&parts),
+            vec![vec!["config.json", "a.data"], vec!["b.data", "c.data"], vec!["d.data"]]
+        );
+        for part in &parts[..2] {
+            let size: u64 = part.iter().map(|(_, v)| v.size + FILE_OVERHEAD).sum();
+            assert!(PART_OVERHEAD + size <= max_report_size);
+        }
+
+        // Compression may grow the profiles.
+        let parts = split_profiles(
+            profile_dir.path(),
+            max_report_size,
+            ReportCompression::Deflate,
+            &index,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            names(&parts),
+            vec![vec!["config.json", "a.data"], vec!["b.data"], vec!["c.data"], vec!["d.data"]]
+        );
+
+        let parts = split_profiles(profile_dir.path(), 0, ReportCompression::Stored, &index, false)
+            .unwrap();
+        assert_eq!(
+            names(&parts),
+            vec![vec!["config.json", "a.data", "b.data", "c.data", "d.data"]]
+        );
+    }
+
+    #[test]
+    fn nothing_to_split_without_new_profiles() {
+        let profile_dir = TempDir::new().unwrap();
+        fs::write(profile_dir.path().join("config.json"), b"{}").unwrap();
+        let profile = profile_dir.path().join("a.data");
+        fs::write(&profile, b"profile").unwrap();
+        let mut index = ReportIndex::default();
+        index.mark_reported(&profile, ProfileVersion::new(&profile).unwrap(), "report");
+
+        let split = |full| {
+            split_profiles(profile_dir.path(), 0, ReportCompression::Stored, &index, full).unwrap()
+        };
+        assert!(split(false).is_empty());
+        assert_eq!(names(&split(true)), vec![vec!["config.json", "a.data"]]);
+    }
+
+    #[test]
+    fn multi_part_reports() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        let mut config = Config::for_test();
+        config.report_compression = ReportCompression::Stored;
+        config.max_report_size = PART_OVERHEAD + 2 * (1000 + FILE_OVERHEAD);
+        let profiles = ["a.data", "b.data", "c.data", "d.data", "e.data"];
+        for name in &profiles {
+            fs::write(profile_dir.path().join(name), vec![0u8; 1000]).unwrap();
+        }
+
+        let mut index = ReportIndex::default();
+        let names = pack(profile_dir.path(), report_dir.path(), &config, &mut index).unwrap();
+        assert_eq!(names.len(), 3);
+        let report_set = Uuid::parse_str(&names[0][..36]).unwrap().to_string();
+        let mut packed = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(*name, format!("{}-{}", report_set, i + 1));
+            let report = get_report_path(report_dir.path(), name);
+            assert_eq!(report.file_name().unwrap().to_str().unwrap(), format!("{}.zip", name));
+            assert!(report.metadata().unwrap().len() <= config.max_report_size);
+            // Each part stands on its own.
+            crate::signing::verify_report(&report).unwrap();
+            let manifest = read_manifest(&report);
+            assert_eq!(manifest.report_set, report_set);
+            assert_eq!((manifest.part, manifest.parts), (i as u32 + 1, 3));
+            packed.extend(manifest.files.into_iter().map(|f| f.name));
+        }
+        assert_eq!(packed, profiles);
+
+        // Everything was reported.
+        assert!(pack(profile_dir.path(), report_dir.path(), &config, &mut index)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn merged_profiles_reported_once() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        let config = Config::for_test();
+        let merged = profile_dir.path().join("merged_libc.so_0123456789abcdef.data");
+        fs::write(profile_dir.path().join("config.json"), b"{}").unwrap();
+        fs::write(&merged, b"generation 1").unwrap();
+        fs::write(profile_dir.path().join("20210601-120000_periodic.data"), b"trace").unwrap();
+
+        let mut index = ReportIndex::default();
+        let first = pack(profile_dir.path(), report_dir.path(), &config, &mut index).unwrap();
+        let sealed = format!("{}.merged_libc.so_0123456789abcdef.data", first[0]);
+        assert_eq!(
+            list_dir(profile_dir.path()),
+            vec![&sealed, "20210601-120000_periodic.data", "config.json"]
+        );
+
+        // New traces are merged into a new generation, reported on its own.
+        fs::write(&merged, b"generation 2").unwrap();
+        let second = pack(profile_dir.path(), report_dir.path(), &config, &mut index).unwrap();
+        let files: Vec<_> = read_manifest(&get_report_path(report_dir.path(), &second[0]))
+            .files
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(files, vec!["config.json", "merged_libc.so_0123456789abcdef.data"]);
+        assert!(!merged.exists());
+
+        // Delivering the first report deletes the first generation and the trace profile.
+        index.remove_reported(&first[0], profile_dir.path()).unwrap();
+        assert_eq!(
+            list_dir(profile_dir.path()),
+            vec![
+                format!("{}.merged_libc.so_0123456789abcdef.data", second[0]),
+                "config.json".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn triggers_listed_in_manifest() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        let config = Config::for_test();
+        let trigger = profile_dir.path().join("20210601-120000_psi_cpu.trigger");
+        fs::write(&trigger, br#"{"resource": "cpu", "threshold": 10}"#).unwrap();
+        fs::write(profile_dir.path().join("20210601-120100_psi_io.trigger"), b"{").unwrap();
+
+        // Triggers wait for the profiles of their traces.
+        let mut index = ReportIndex::default();
+        assert!(pack(profile_dir.path(), report_dir.path(), &config, &mut index)
+            .unwrap()
+            .is_empty());
+
+        let merged = profile_dir.path().join("merged_libc.so_0123456789abcdef.data");
+        fs::write(&merged, b"generation 1").unwrap();
+        let first = pack(profile_dir.path(), report_dir.path(), &config, &mut index).unwrap();
+        let manifest = read_manifest(&get_report_path(report_dir.path(), &first[0]));
+        let files: Vec<_> = manifest.files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(files, vec!["merged_libc.so_0123456789abcdef.data"]);
+        // Unreadable events are left for the next report.
+        assert_eq!(manifest.triggers.len(), 1);
+        assert_eq!(manifest.triggers[0].tag.as_deref(), Some("psi_cpu"));
+        assert_eq!(manifest.triggers[0].captured.as_deref(), Some("2021-06-01T12:00:00+00:00"));
+        assert_eq!(
+            manifest.triggers[0].event,
+            serde_json::json!({"resource": "cpu", "threshold": 10})
+        );
+
+        // Reported once, and deleted with the generation of the profile they contributed to.
+        fs::write(&merged, b"generation 2").unwrap();
+        let second = pack(profile_dir.path(), report_dir.path(), &config, &mut index).unwrap();
+        assert!(read_manifest(&get_report_path(report_dir.path(), &second[0])).triggers.is_empty());
+        index.remove_reported(&first[0], profile_dir.path()).unwrap();
+        assert!(!trigger.exists());
+    }
+
+    #[test]
+    fn report_names_hold_creation_time() {
+        let dir = TempDir::new().unwrap();
+        let node_id = MacAddr6::new(1, 2, 3, 4, 5, 6);
+        let created =
+            SystemTime::from("2021-06-01T12:00:00.250Z".parse::<DateTime<Utc>>().unwrap());
+        for naming in &[ReportNaming::Uuid1, ReportNaming::Uuid7] {
+            let report_set = get_report_set_id(*naming, &node_id, created).unwrap();
+            let report = get_report_path(dir.path(), &format!("{}-1", report_set));
+            assert_eq!(get_report_ts(&report).unwrap(), created, "{}", naming);
+        }
+    }
+
+    #[test]
+    fn random_report_names_use_file_time() {
+        let dir = TempDir::new().unwrap();
+        let created = SystemTime::from("2021-06-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap());
+        let report_set = get_report_set_id(ReportNaming::Uuid4, &MacAddr6::nil(), created).unwrap();
+        let report = get_report_path(dir.path(), &report_set);
+        fs::write(&report, b"").unwrap();
+        assert_eq!(get_report_ts(&report).unwrap(), report.metadata().unwrap().modified().unwrap());
+    }
 }
@@ -0,0 +1,226 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Index of the profiles already packed into a report, so that consecutive reports don't overlap.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, read_to_string, remove_file, rename};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Size and modification time of a profile, used to tell whether it changed after it was
+/// reported.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ProfileVersion {
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl ProfileVersion {
+    pub fn new(profile: &Path) -> Result<Self> {
+        let metadata = profile.metadata()?;
+        Ok(ProfileVersion { size: metadata.len(), modified: metadata.modified()? })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ReportedProfile {
+    /// Name of the report the profile was packed into.
+    report: String,
+    version: ProfileVersion,
+}
+
+/// Stored as REPORT_INDEX_FILE, keyed by profile file name.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ReportIndex {
+    profiles: BTreeMap<String, ReportedProfile>,
+}
+
+impl ReportIndex {
+    /// Load the index, starting over with an empty one if it is missing or unreadable.
+    pub fn load(path: &Path) -> Self {
+        match read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::error!("Discarding unreadable report index {}: {}", path.display(), e);
+                ReportIndex::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => ReportIndex::default(),
+            Err(e) => {
+                log::error!("Failed to read report index {}: {}", path.display(), e);
+                ReportIndex::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Whether `profile` was packed into a report and hasn't changed since.
+    pub fn is_reported(&self, profile: &Path, version: &ProfileVersion) -> bool {
+        matches!(
+            get_name(profile).and_then(|name| self.profiles.get(name)),
+            Some(reported) if reported.version == *version
+        )
+    }
+
+    pub fn mark_reported(&mut self, profile: &Path, version: ProfileVersion, report: &str) {
+        if let Some(name) = get_name(profile) {
+            self.profiles
+                .insert(name.to_string(), ReportedProfile { report: report.to_string(), version });
+        }
+    }
+
+    /// Forget profiles that no longer exist in `profile_dir`.
+    pub fn retain_profiles(&mut self, profile_dir: &Path) {
+        self.profiles.retain(|name, _| profile_dir.join(name).exists());
+    }
+
+    /// Forget profiles packed into reports that no longer exist in `report_dir`, e.g. dropped past
+    /// their retention period without being uploaded. Those profiles will be reported again.
+    pub fn retain_reports(&mut self, report_dir: &Path) {
+        self.profiles.retain(|_, reported| {
+            let mut report = PathBuf::from(report_dir);
+            report.push(&reported.report);
+            report.set_extension("zip");
+            report.exists()
+        });
+    }
+
+    /// Delete the profiles packed into `report` from `profile_dir`, once the report has been
+    /// delivered. Profiles updated since they were reported are kept, to be reported again.
+    pub fn remove_reported(&mut self, report: &str, profile_dir: &Path) -> Result<()> {
+        let names: Vec<String> = self
+            .profiles
+            .iter()
+            .filter(|(_, reported)| reported.report == report)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            let reported = self.profiles.remove(&name).unwrap();
+            let profile = profile_dir.join(&name);
+            match ProfileVersion::new(&profile) {
+                Ok(version) if version == reported.version => remove_file(&profile)?,
+                Ok(_) => log::info!("Profile {} changed since it was reported, keeping", name),
+                Err(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get_name(profile: &Path) -> Option<&str> {
+    profile.file_name().and_then(|f| f.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn add_profile(dir: &Path, name: &str, contents: &[u8]) -> (PathBuf, ProfileVersion) {
+        let profile = dir.join(name);
+        fs::write(&profile, contents).unwrap();
+        let version = ProfileVersion::new(&profile).unwrap();
+        (profile, version)
+    }
+
+    #[test]
+    fn reported_until_changed() {
+        let dir = TempDir::new().unwrap();
+        let (profile, version) = add_profile(dir.path(), "a.data", b"a");
+        let mut index = ReportIndex::default();
+        assert!(!index.is_reported(&profile, &version));
+
+        index.mark_reported(&profile, version, "report-1");
+        assert!(index.is_reported(&profile, &version));
+        let (_, changed) = add_profile(dir.path(), "a.data", b"ab");
+        assert!(!index.is_reported(&profile, &changed));
+        assert!(!index.is_reported(&dir.path().join("b.data"), &version));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let (profile, version) = add_profile(dir.path(), "a.data", b"a");
+        let index_file = dir.path().join("index.json");
+        let mut index = ReportIndex::default();
+        index.mark_reported(&profile, version, "report-1");
+        index.save(&index_file).unwrap();
+        assert!(!index_file.with_extension("tmp").exists());
+        assert!(ReportIndex::load(&index_file).is_reported(&profile, &version));
+
+        // Missing or corrupt indexes start over.
+        assert!(
+            !ReportIndex::load(&dir.path().join("missing.json")).is_reported(&profile, &version)
+        );
+        fs::write(&index_file, b"{").unwrap();
+        assert!(!ReportIndex::load(&index_file).is_reported(&profile, &version));
+    }
+
+    #[test]
+    fn forget_missing_profiles_and_reports() {
+        let profile_dir = TempDir::new().unwrap();
+        let report_dir = TempDir::new().unwrap();
+        let (a, a_version) = add_profile(profile_dir.path(), "a.data", b"a");
+        let (b, b_version) = add_profile(profile_dir.path(), "b.data", b"b");
+        fs::write(report_dir.path().join("report-1.zip"), b"").unwrap();
+        let mut index = ReportIndex::default();
+        index.mark_reported(&a, a_version, "report-1");
+        index.mark_reported(&b, b_version, "report-2");
+
+        // report-2 was dropped without being delivered, its profile will be reported again.
+        index.retain_reports(report_dir.path());
+        assert!(index.is_reported(&a, &a_version));
+        assert!(!index.is_reported(&b, &b_version));
+
+        fs::remove_file(&a).unwrap();
+        index.retain_profiles(profile_dir.path());
+        assert!(index.profiles.is_empty());
+    }
+
+    #[test]
+    fn remove_delivered_profiles() {
+        let dir = TempDir::new().unwrap();
+        let (a, a_version) = add_profile(dir.path(), "a.data", b"a");
+        let (b, b_version) = add_profile(dir.path(), "b.data", b"b");
+        let (c, c_version) = add_profile(dir.path(), "c.data", b"c");
+        let mut index = ReportIndex::default();
+        index.mark_reported(&a, a_version, "report-1");
+        index.mark_reported(&b, b_version, "report-1");
+        index.mark_reported(&c, c_version, "report-2");
+        // Updated after it was reported.
+        add_profile(dir.path(), "b.data", b"bb");
+
+        index.remove_reported("report-1", dir.path()).unwrap();
+        assert!(!a.exists());
+        assert!(b.exists());
+        assert!(c.exists());
+        assert!(!index.is_reported(&b, &b_version));
+        assert!(index.is_reported(&c, &c_version));
+
+        // Removing a report twice, or one that was never made, does nothing.
+        index.remove_reported("report-1", dir.path()).unwrap();
+        index.remove_reported("report-3", dir.path()).unwrap();
+        assert!(b.exists() && c.exists());
+    }
+}
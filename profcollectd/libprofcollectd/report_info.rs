@@ -0,0 +1,279 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Description of stored reports, returned through list_reports and inspect_report.
+
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use zip::ZipArchive;
+
+use crate::config::REPORT_RETENTION_SECS;
+use crate::encryption::ENVELOPE_MAGIC;
+use crate::manifest::MANIFEST_FILENAME;
+use crate::report::{get_report_path, get_report_ts};
+
+#[derive(Serialize)]
+pub struct ReportInfo {
+    pub name: String,
+    /// RFC 3339 creation time.
+    pub created: String,
+    pub size: u64,
+    pub encrypted: bool,
+    /// Number of files in the report, None if it is encrypted.
+    pub files: Option<usize>,
+    /// Time left before the report is deleted at startup.
+    pub retention_left_secs: u64,
+}
+
+impl ReportInfo {
+    fn new(report: &Path, name: &str, created: SystemTime) -> Result<Self> {
+        let retention_left = Duration::from_secs(REPORT_RETENTION_SECS)
+            .checked_sub(SystemTime::now().duration_since(created).unwrap_or_default())
+            .unwrap_or_default();
+        let encrypted = is_encrypted(report)?;
+        let files = match encrypted {
+            true => None,
+            false => Some(ZipArchive::new(File::open(report)?)?.len()),
+        };
+        Ok(ReportInfo {
+            name: name.to_string(),
+            created: DateTime::<Utc>::from(created).to_rfc3339(),
+            size: report.metadata()?.len(),
+            encrypted,
+            files,
+            retention_left_secs: retention_left.as_secs(),
+        })
+    }
+}
+
+fn is_encrypted(report: &Path) -> Result<bool> {
+    let mut magic = [0u8; 8];
+    Ok(File::open(report)?.read_exact(&mut magic).is_ok() && &magic == ENVELOPE_MAGIC)
+}
+
+/// Describe the reports in `report_dir`, oldest first.
+pub fn list_reports(report_dir: &Path) -> Result<Vec<ReportInfo>> {
+    let mut reports = Vec::new();
+    for entry in read_dir(report_dir)? {
+        let report = entry?.path();
+        if report.extension().and_then(|e| e.to_str()) != Some("zip") {
+            continue;
+        }
+        if let Some(name) = report.file_stem().and_then(|s| s.to_str()) {
+            let info = get_report_ts(&report)
+                .and_then(|created| Ok((created, ReportInfo::new(&report, name, created)?)));
+            match info {
+                Ok(info) => reports.push(info),
+                Err(e) => log::error!("Cannot describe report {}: {:#}", name, e),
+            }
+        }
+    }
+    // Parts of a report set share their creation time.
+    reports.sort_by(|(a_ts, a), (b_ts, b)| a_ts.cmp(b_ts).then_with(|| a.name.cmp(&b.name)));
+    Ok(reports.into_iter().map(|(_, info)| info).collect())
+}
+
+#[derive(Serialize)]
+pub struct ReportEntry {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+}
+
+/// Contents of a report, as printed by `profcollectctl inspect`.
+#[derive(Serialize)]
+pub struct ReportContents {
+    #[serde(flatten)]
+    pub info: ReportInfo,
+    /// Empty if the report is encrypted.
+    pub entries: Vec<ReportEntry>,
+    /// Kept as plain JSON, so that reports written with an older config scheme can be inspected.
+    pub manifest: Option<serde_json::Value>,
+}
+
+impl ToString for ReportContents {
+    fn to_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialise report contents.")
+    }
+}
+
+/// Describe the report `report_name` in `report_dir`, with its file list and manifest.
+pub fn inspect_report(report_dir: &Path, report_name: &str) -> Result<ReportContents> {
+    let report = get_report_path(report_dir, report_name);
+    ensure!(report.is_file(), "No such report: {}", report_name);
+    let info = ReportInfo::new(&report, report_name, get_report_ts(&report)?)?;
+    if info.encrypted {
+        return Ok(ReportContents { info, entries: Vec::new(), manifest: None });
+    }
+
+    let mut zip = ZipArchive::new(File::open(&report)?).context("Not a valid report")?;
+    let mut entries = Vec::new();
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        entries.push(ReportEntry {
+            name: file.name().to_string(),
+            size: file.size(),
+            compressed_size: file.compressed_size(),
+        });
+    }
+    let manifest = match zip.by_name(MANIFEST_FILENAME) {
+        Ok(file) => Some(serde_json::from_reader(file).context("Malformed manifest")?),
+        Err(_) => None,
+    };
+    Ok(ReportContents { info, entries, manifest })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::io::Write;
+    use std::time::UNIX_EPOCH;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// The name of a report set created at `created`, as a version 7 UUID.
+    fn report_set(created: SystemTime) -> String {
+        let millis = created.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        format!("{:08x}-{:04x}-7000-8000-000000000000", millis >> 16, millis & 0xffff)
+    }
+
+    /// Whole seconds, `days_ago` days ago.
+    fn days_ago(days_ago: u64) -> SystemTime {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        UNIX_EPOCH + Duration::from_secs(now - days_ago * 24 * 60 * 60)
+    }
+
+    fn write_report(report_dir: &Path, name: &str, files: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(get_report_path(report_dir, name)).unwrap());
+        for (file, contents) in files {
+            zip.start_file(*file, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn write_encrypted_report(report_dir: &Path, name: &str) {
+        let mut contents = ENVELOPE_MAGIC.to_vec();
+        contents.extend_from_slice(&[0; 64]);
+        write(get_report_path(report_dir, name), contents).unwrap();
+    }
+
+    #[test]
+    fn list_reports_oldest_first() {
+        let report_dir = TempDir::new().unwrap();
+        let (older, newer) = (days_ago(2), days_ago(1));
+        let older_set = report_set(older);
+        let newer_set = report_set(newer);
+        write_report(report_dir.path(), &format!("{}-2", older_set), &[("b", b"b")]);
+        write_report(report_dir.path(), &format!("{}-1", older_set), &[("a", b"a"), ("c", b"")]);
+        write_encrypted_report(report_dir.path(), &newer_set);
+        // Not reports, ignored.
+        write(report_dir.path().join(format!("{}.tmp", newer_set)), b"").unwrap();
+        write(report_dir.path().join("garbage.zip"), b"").unwrap();
+
+        let reports = list_reports(report_dir.path()).unwrap();
+        let names: Vec<_> = reports.iter().map(|r| r.name.clone()).collect();
+        assert_eq!(names, vec![format!("{}-1", older_set), format!("{}-2", older_set), newer_set]);
+
+        assert_eq!(reports[0].files, Some(2));
+        assert_eq!(reports[1].files, Some(1));
+        assert_eq!(reports[2].files, None);
+        assert_eq!(reports.iter().map(|r| r.encrypted).collect::<Vec<_>>(), [false, false, true]);
+        assert_eq!(reports[2].size, ENVELOPE_MAGIC.len() as u64 + 64);
+
+        assert_eq!(reports[0].created, DateTime::<Utc>::from(older).to_rfc3339());
+        assert_eq!(reports[2].created, DateTime::<Utc>::from(newer).to_rfc3339());
+        let retention_left = REPORT_RETENTION_SECS - 24 * 60 * 60;
+        assert!((retention_left - 60..=retention_left).contains(&reports[2].retention_left_secs));
+    }
+
+    #[test]
+    fn list_expired_reports() {
+        let report_dir = TempDir::new().unwrap();
+        let created = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        write_report(report_dir.path(), &report_set(created), &[]);
+        let reports = list_reports(report_dir.path()).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].retention_left_secs, 0);
+    }
+
+    #[test]
+    fn inspect() {
+        let report_dir = TempDir::new().unwrap();
+        let name = report_set(days_ago(1));
+        let manifest = br#"{"version": 1, "profiles": []}"#;
+        write_report(
+            report_dir.path(),
+            &name,
+            &[("profile", b"profile data"), (MANIFEST_FILENAME, manifest)],
+        );
+
+        let contents = inspect_report(report_dir.path(), &name).unwrap();
+        assert_eq!(contents.info.name, name);
+        assert_eq!(contents.info.files, Some(2));
+        let entries: Vec<_> = contents.entries.iter().map(|e| (e.name.as_str(), e.size)).collect();
+        assert_eq!(entries, vec![("profile", 12), (MANIFEST_FILENAME, manifest.len() as u64)]);
+        assert_eq!(contents.manifest, Some(serde_json::json!({"version": 1, "profiles": []})));
+
+        // Printed with the report info alongside the entries.
+        let printed: serde_json::Value = serde_json::from_str(&contents.to_string()).unwrap();
+        assert_eq!(printed["name"], serde_json::json!(name));
+        assert_eq!(printed["entries"][0]["name"], serde_json::json!("profile"));
+        assert_eq!(printed["manifest"]["version"], serde_json::json!(1));
+    }
+
+    #[test]
+    fn inspect_without_manifest() {
+        let report_dir = TempDir::new().unwrap();
+        let name = report_set(days_ago(1));
+        write_report(report_dir.path(), &name, &[("profile", b"")]);
+        let contents = inspect_report(report_dir.path(), &name).unwrap();
+        assert_eq!(contents.entries.len(), 1);
+        assert_eq!(contents.manifest, None);
+    }
+
+    #[test]
+    fn inspect_encrypted() {
+        let report_dir = TempDir::new().unwrap();
+        let name = report_set(days_ago(1));
+        write_encrypted_report(report_dir.path(), &name);
+        let contents = inspect_report(report_dir.path(), &name).unwrap();
+        assert!(contents.info.encrypted);
+        assert!(contents.entries.is_empty());
+        assert_eq!(contents.manifest, None);
+    }
+
+    #[test]
+    fn inspect_invalid_reports() {
+        let report_dir = TempDir::new().unwrap();
+        assert!(inspect_report(report_dir.path(), &report_set(days_ago(1))).is_err());
+
+        let malformed_manifest = report_set(days_ago(2));
+        write_report(report_dir.path(), &malformed_manifest, &[(MANIFEST_FILENAME, b"{")]);
+        assert!(inspect_report(report_dir.path(), &malformed_manifest).is_err());
+
+        let not_zip = report_set(days_ago(3));
+        write(get_report_path(report_dir.path(), &not_zip), b"not a zip").unwrap();
+        assert!(inspect_report(report_dir.path(), &not_zip).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Destinations reports are exported to.
+
+use anyhow::{bail, ensure, Context, Result};
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+
+use crate::config::{BETTERBUG_CACHE_DIR_PREFIX, BETTERBUG_CACHE_DIR_SUFFIX, EXPORT_DIR_ALLOWLIST};
+use crate::http::{post_file, HttpUrl};
+
+pub trait ReportSink {
+    fn get_name(&self) -> &'static str;
+    /// Export the report file `report`, named `report_name`.
+    fn export(&self, report: &Path, report_name: &str) -> Result<()>;
+}
+
+/// Parse the destinations configured through the "report_sinks" flag, a list separated by ';' of
+/// either "dir:<path>" for an allow-listed directory, or "http://<host>:<port>/<path>" for a
+/// local HTTP endpoint.
+pub fn parse_report_sinks(sinks: &str) -> Result<Vec<Box<dyn ReportSink>>> {
+    sinks
+        .split(';')
+        .map(str::trim)
+        .filter(|sink| !sink.is_empty())
+        .map(|sink| -> Result<Box<dyn ReportSink>> {
+            if let Some(dir) = sink.strip_prefix("dir:") {
+                Ok(Box::new(DirectorySink::new(Path::new(dir))?))
+            } else if sink.starts_with("http://") {
+                Ok(Box::new(HttpSink::new(sink)?))
+            } else {
+                bail!("Unknown report sink: {}", sink)
+            }
+        })
+        .collect()
+}
+
+/// Copy reports into a directory under one of `EXPORT_DIR_ALLOWLIST`.
+pub struct DirectorySink {
+    dir: PathBuf,
+    allowlist: Vec<PathBuf>,
+}
+
+impl DirectorySink {
+    pub fn new(dir: &Path) -> Result<Self> {
+        Self::with_allowlist(dir, &EXPORT_DIR_ALLOWLIST)
+    }
+
+    fn with_allowlist(dir: &Path, allowlist: &[&Path]) -> Result<Self> {
+        ensure!(dir.is_absolute(), "Export directory is not absolute: {}", dir.display());
+        // Reject "." and ".." rather than resolving them, see also the symlink check on export.
+        ensure!(
+            dir.components().all(|c| matches!(c, Component::RootDir | Component::Normal(_))),
+            "Export directory is not normalized: {}",
+            dir.display()
+        );
+        let sink = DirectorySink {
+            dir: dir.to_path_buf(),
+            allowlist: allowlist.iter().map(|d| d.to_path_buf()).collect(),
+        };
+        ensure!(sink.is_allowed_dir(dir), "Export directory is not allowed: {}", dir.display());
+        Ok(sink)
+    }
+
+    /// The BetterBug cache of the given user profile.
+    pub fn betterbug(bb_profile_id: i32) -> Result<Self> {
+        ensure!(bb_profile_id >= 0, "Invalid profile ID");
+        let mut dir = PathBuf::from(&*BETTERBUG_CACHE_DIR_PREFIX);
+        dir.push(bb_profile_id.to_string());
+        dir.push(&*BETTERBUG_CACHE_DIR_SUFFIX);
+        Self::new(&dir)
+    }
+
+    fn is_allowed_dir(&self, dir: &Path) -> bool {
+        self.allowlist.iter().any(|allowed| is_under_pattern(dir, allowed))
+    }
+}
+
+/// Whether `dir` is `pattern` or a subdirectory of it, where a "*" component of `pattern` matches
+/// any user id.
+fn is_under_pattern(dir: &Path, pattern: &Path) -> bool {
+    let mut dir = dir.components();
+    pattern.components().all(|expected| match (expected, dir.next()) {
+        (Component::Normal(expected), Some(Component::Normal(user_id))) if expected == "*" => {
+            is_user_id(user_id)
+        }
+        (expected, Some(component)) => expected == component,
+        (_, None) => false,
+    })
+}
+
+fn is_user_id(name: &OsStr) -> bool {
+    !name.is_empty() && name.as_bytes().iter().all(u8::is_ascii_digit)
+}
+
+impl ReportSink for DirectorySink {
+    fn get_name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn export(&self, report: &Path, report_name: &str) -> Result<()> {
+        ensure!(
+            matches!(
+                Path::new(report_name).components().collect::<Vec<_>>()[..],
+                [Component::Normal(_)]
+            ),
+            "Invalid report name: {}",
+            report_name
+        );
+        // Symlinks may point out of the allow-list, check where they lead. The directory is then
+        // opened without following symlinks, so that none can be swapped in after the check.
+        let dir = self
+            .dir
+            .canonicalize()
+            .with_context(|| format!("Cannot open export directory {}", self.dir.display()))?;
+        ensure!(self.is_allowed_dir(&dir), "Export directory is not allowed: {}", dir.display());
+        let dir_file = open_dir_nofollow(&dir)?;
+
+        let dest = format!("{}.zip", report_name);
+        let tmp_dest = format!("{}.tmp", report_name);
+        copy_into_dir(report, &dir_file, &tmp_dest)
+            .and_then(|_| rename_in_dir(&dir_file, &tmp_dest, &dest))
+            .map_err(|e| {
+                unlink_in_dir(&dir_file, &tmp_dest).ok();
+                e
+            })
+            .with_context(|| format!("Failed to copy report to {}", dir.display()))
+    }
+}
+
+fn to_cstring(name: &OsStr) -> Result<CString> {
+    Ok(CString::new(name.as_bytes())?)
+}
+
+/// Open the directory `dir`, an absolute path without "." or "..", failing if any of its
+/// components is a symlink.
+fn open_dir_nofollow(dir: &Path) -> Result<File> {
+    let mut current = File::open("/")?;
+    for component in dir.components() {
+        let name = match component {
+            Component::RootDir => continue,
+            Component::Normal(name) => to_cstring(name)?,
+            _ => bail!("Export directory is not normalized: {}", dir.display()),
+        };
+        // SAFETY: `name` is NUL terminated and `current` is an open directory.
+        let fd = unsafe {
+            libc::openat(
+                current.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Cannot open export directory {}", dir.display()));
+        }
+        // SAFETY: `fd` was just opened and is owned by nobody else.
+        current = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(current)
+}
+
+/// Copy `report` to a new file `name` in the directory `dir`, replacing any file or symlink there.
+fn copy_into_dir(report: &Path, dir: &File, name: &str) -> Result<()> {
+    unlink_in_dir(dir, name).ok();
+    let name = to_cstring(OsStr::new(name))?;
+    // SAFETY: `name` is NUL terminated and `dir` is an open directory.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            0o644 as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // SAFETY: `fd` was just opened and is owned by nobody else.
+    let mut dest = unsafe { File::from_raw_fd(fd) };
+    io::copy(&mut File::open(report)?, &mut dest)?;
+    dest.sync_all()?;
+    Ok(())
+}
+
+fn rename_in_dir(dir: &File, from: &str, to: &str) -> Result<()> {
+    let (from, to) = (to_cstring(OsStr::new(from))?, to_cstring(OsStr::new(to))?);
+    // SAFETY: both names are NUL terminated and `dir` is an open directory.
+    match unsafe { libc::renameat(dir.as_raw_fd(), from.as_ptr(), dir.as_raw_fd(), to.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().into()),
+    }
+}
+
+fn unlink_in_dir(dir: &File, name: &str) -> Result<()> {
+    let name = to_cstring(OsStr::new(name))?;
+    // SAFETY: `name` is NUL terminated and `dir` is an open directory.
+    match unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error().into()),
+    }
+}
+
+/// Write reports into a file descriptor handed over through binder.
+pub struct FileDescriptorSink {
+    file: File,
+}
+
+impl FileDescriptorSink {
+    pub fn new(file: File) -> Self {
+        FileDescriptorSink { file }
+    }
+}
+
+impl ReportSink for FileDescriptorSink {
+    fn get_name(&self) -> &'static str {
+        "file_descriptor"
+    }
+
+    fn export(&self, report: &Path, _report_name: &str) -> Result<()> {
+        let mut dest = &self.file;
+        io::copy(&mut File::open(report)?, &mut dest)?;
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+/// POST reports to an HTTP endpoint on the loopback interface.
+pub struct HttpSink {
+    url: HttpUrl,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Result<Self> {
+        let parsed = HttpUrl::parse(url)?;
+        // Reports must not leave the device through this sink. The host is only resolved when
+        // exporting, so it must be localhost or a loopback address.
+        let is_loopback = parsed.host == "localhost"
+            || matches!(parsed.get_bare_host().parse::<IpAddr>(), Ok(ip) if ip.is_loopback());
+        ensure!(!parsed.https && is_loopback, "HTTP endpoint is not local: {}", url);
+        Ok(HttpSink { url: parsed })
+    }
+}
+
+impl ReportSink for HttpSink {
+    fn get_name(&self) -> &'static str {
+        "http"
+    }
+
+    fn export(&self, report: &Path, report_name: &str) -> Result<()> {
+        let headers = [("X-Profcollect-Report", report_name)];
+        let status = post_file(&self.url, report, &headers, &AtomicBool::new(false))?;
+        ensure!((200..300).contains(&status), "HTTP endpoint returned {}", status);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    /// An allow-listed app cache directory, laid out as on the device, and a directory outside
+    /// of the allow-list.
+    struct Dirs {
+        root: PathBuf,
+        cache: PathBuf,
+        outside: PathBuf,
+        report: PathBuf,
+        _tmp: TempDir,
+    }
+
+    fn setup() -> Dirs {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let (cache, outside) = (root.join("data/app/cache"), root.join("outside"));
+        fs::create_dir_all(&cache).unwrap();
+        fs::create_dir_all(root.join("data/other/cache")).unwrap();
+        fs::create_dir_all(root.join("user/10/app/cache")).unwrap();
+        symlink(root.join("data"), root.join("user/0")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        let report = root.join("report.zip");
+        fs::write(&report, b"report").unwrap();
+        Dirs { root, cache, outside, report, _tmp: tmp }
+    }
+
+    fn sink(dirs: &Dirs, dir: &Path) -> Result<DirectorySink> {
+        DirectorySink::with_allowlist(
+            dir,
+            &[&dirs.root.join("user/*/app/cache/"), &dirs.root.join("data/app/cache/")],
+        )
+    }
+
+    #[test]
+    fn export() {
+        let dirs = setup();
+        for dest in &[dirs.root.join("user/10/app/cache"), dirs.cache.join("reports")] {
+            fs::create_dir_all(dest).unwrap();
+            sink(&dirs, dest).unwrap().export(&dirs.report, "0123-1").unwrap();
+            assert_eq!(fs::read(dest.join("0123-1.zip")).unwrap(), b"report");
+            assert_eq!(fs::read_dir(dest).unwrap().count(), 1);
+        }
+    }
+
+    #[test]
+    fn reject_dirs_outside_allowlist() {
+        let dirs = setup();
+        for dir in &[
+            PathBuf::from("data/app/cache"),
+            dirs.root.join("user/10/other/cache"),
+            dirs.root.join("data/other/cache"),
+            dirs.root.join("user/abc/app/cache"),
+            dirs.root.join("user/10/app"),
+            dirs.root.join("user"),
+            dirs.outside.clone(),
+            PathBuf::from("/etc"),
+            dirs.cache.join("../../other/cache"),
+            dirs.cache.join("reports").join(".."),
+        ] {
+            assert!(sink(&dirs, dir).is_err(), "{}", dir.display());
+        }
+    }
+
+    #[test]
+    fn reject_report_names_escaping_dir() {
+        let dirs = setup();
+        let sink = sink(&dirs, &dirs.cache).unwrap();
+        let escape = format!("{}/report", dirs.outside.display());
+        for name in &["..", ".", "", "../../../outside/report", "app/report", &escape] {
+            assert!(sink.export(&dirs.report, name).is_err(), "{}", name);
+        }
+        assert_eq!(fs::read_dir(&dirs.outside).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(&dirs.cache).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn reject_symlinks_out_of_allowlist() {
+        let dirs = setup();
+        for target in &[dirs.outside.clone(), dirs.root.join("data/other/cache")] {
+            let link = dirs.cache.join("link");
+            symlink(target, &link).unwrap();
+            let sink = sink(&dirs, &link).unwrap();
+            assert!(sink.export(&dirs.report, "0123-1").is_err());
+            assert_eq!(fs::read_dir(target).unwrap().count(), 0);
+            fs::remove_file(&link).unwrap();
+        }
+    }
+
+    #[test]
+    fn follow_symlinks_within_allowlist() {
+        // user/0 links to data, like /data/user/0 to /data/data.
+        let dirs = setup();
+        sink(&dirs, &dirs.root.join("user/0/app/cache"))
+            .unwrap()
+            .export(&dirs.report, "0123-1")
+            .unwrap();
+        assert_eq!(fs::read(dirs.cache.join("0123-1.zip")).unwrap(), b"report");
+    }
+
+    #[test]
+    fn replace_symlinked_files() {
+        let dirs = setup();
+        let dest = &dirs.cache;
+        let victim = dirs.outside.join("victim");
+        fs::write(&victim, b"victim").unwrap();
+        symlink(&victim, dest.join("0123-1.zip")).unwrap();
+        symlink(&victim, dest.join("0123-1.tmp")).unwrap();
+
+        sink(&dirs, dest).unwrap().export(&dirs.report, "0123-1").unwrap();
+        assert_eq!(fs::read(&victim).unwrap(), b"victim");
+        let exported = dest.join("0123-1.zip");
+        assert!(!exported.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(&exported).unwrap(), b"report");
+        assert!(!dest.join("0123-1.tmp").exists());
+    }
+
+    #[test]
+    fn open_dir_rejects_symlinks() {
+        let dirs = setup();
+        assert!(open_dir_nofollow(&dirs.cache).is_ok());
+        let link = dirs.cache.join("link");
+        symlink(&dirs.cache, &link).unwrap();
+        assert!(open_dir_nofollow(&link).is_err());
+        assert!(open_dir_nofollow(&link.join("link")).is_err());
+        assert!(open_dir_nofollow(&dirs.root.join("user/0/app/cache")).is_err());
+    }
+
+    #[test]
+    fn missing_dir() {
+        let dirs = setup();
+        let sink = sink(&dirs, &dirs.cache.join("missing")).unwrap();
+        assert!(sink.export(&dirs.report, "0123-1").is_err());
+    }
+
+    #[test]
+    fn http_sink_only_local() {
+        for url in &["http://127.0.0.1:8080/report", "http://[::1]:8080", "http://localhost/"] {
+            assert!(HttpSink::new(url).is_ok(), "{}", url);
+        }
+        for url in &["http://example.com/report", "http://10.0.0.1/", "https://127.0.0.1/"] {
+            assert!(HttpSink::new(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Incrementally merge sample reports into one aggregated profile per binary.
+//!
+//! Sample reports are the csv output of `simpleperf report --csv`, with one line per binary and
+//! virtual address. Aggregated profiles use the same format, restricted to the columns below.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::branch_list_merger::{get_merged_path, MergedProfiles};
+
+static MERGED_PROFILE_EXTENSION: &str = "samples";
+
+static BINARY_COLUMN: &str = "Shared Object";
+static VADDR_COLUMN: &str = "VaddrInFile";
+static CHILDREN_COLUMN: &str = "Children";
+static SELF_COLUMN: &str = "Self";
+static SAMPLE_COLUMN: &str = "Sample";
+
+/// Event counts at one address, including those of its callees for `children`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct SampleCounts {
+    pub children: u64,
+    pub self_period: u64,
+    pub samples: u64,
+}
+
+impl SampleCounts {
+    fn add(&mut self, other: &SampleCounts) {
+        self.children = self.children.saturating_add(other.children);
+        self.self_period = self.self_period.saturating_add(other.self_period);
+        self.samples = self.samples.saturating_add(other.samples);
+    }
+}
+
+/// Counts by binary path and virtual address in the binary.
+pub type SampleProfile = BTreeMap<String, BTreeMap<u64, SampleCounts>>;
+
+/// Merge every binary in the sample report `samples` into its aggregated profile under
+/// `profile_dir`, summing counts at identical addresses, then delete `samples`.
+pub fn merge_samples(samples: &Path, profile_dir: &Path) -> Result<()> {
+    let new_profile = read_to_string(samples)
+        .map_err(anyhow::Error::from)
+        .and_then(|csv| parse_samples(&csv))
+        .with_context(|| format!("Failed to read samples {}", samples.display()))?;
+
+    let mut updates = MergedProfiles::default();
+    for (binary, addrs) in new_profile {
+        let merged_path =
+            get_merged_path(profile_dir, &binary, binary.as_bytes(), MERGED_PROFILE_EXTENSION);
+        let mut merged = match read_to_string(&merged_path) {
+            Ok(csv) => match parse_samples(&csv) {
+                Ok(mut profile) => profile.remove(&binary).unwrap_or_default(),
+                Err(e) => {
+                    // Start over rather than failing every future merge on a corrupted file.
+                    log::error!("Discarding unreadable profile {}: {}", merged_path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for (addr, counts) in addrs {
+            merged.entry(addr).or_default().add(&counts);
+        }
+
+        updates.write(merged_path, format_samples(&binary, &merged).as_bytes())?;
+    }
+
+    updates.commit(samples)
+}
+
+/// Parse a sample report. Lines before the column names, such as the record command line in
+/// simpleperf output, are skipped.
+pub fn parse_samples(csv: &str) -> Result<SampleProfile> {
+    let mut lines = csv.lines();
+    let header = lines
+        .by_ref()
+        .map(split_csv_line)
+        .find(|fields| fields.contains(&BINARY_COLUMN) && fields.contains(&VADDR_COLUMN))
+        .ok_or_else(|| anyhow!("Missing column names"))?;
+    let column = |name: &str| {
+        header.iter().position(|f| *f == name).ok_or_else(|| anyhow!("Missing column {}", name))
+    };
+    let binary_column = column(BINARY_COLUMN)?;
+    let vaddr_column = column(VADDR_COLUMN)?;
+    let children_column = column(CHILDREN_COLUMN)?;
+    let self_column = column(SELF_COLUMN)?;
+    let sample_column = column(SAMPLE_COLUMN)?;
+
+    let mut profile = SampleProfile::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let fields = split_csv_line(line);
+        let field =
+            |i: usize| fields.get(i).copied().ok_or_else(|| anyhow!("Malformed line: {}", line));
+        let count = |i: usize| -> Result<u64> {
+            field(i)?.parse().with_context(|| format!("Malformed count: {}", line))
+        };
+        let vaddr = field(vaddr_column)?;
+        let vaddr = u64::from_str_radix(vaddr.trim_start_matches("0x"), 16)
+            .with_context(|| format!("Malformed address: {}", line))?;
+        let counts = SampleCounts {
+            children: count(children_column)?,
+            self_period: count(self_column)?,
+            samples: count(sample_column)?,
+        };
+        profile
+            .entry(field(binary_column)?.to_string())
+            .or_default()
+            .entry(vaddr)
+            .or_default()
+            .add(&counts);
+    }
+    Ok(profile)
+}
+
+fn format_samples(binary: &str, addrs: &BTreeMap<u64, SampleCounts>) -> String {
+    let mut csv = format!(
+        "{},{},{},{},{}\n",
+        BINARY_COLUMN, VADDR_COLUMN, CHILDREN_COLUMN, SELF_COLUMN, SAMPLE_COLUMN
+    );
+    // Quoted like simpleperf does, which does not escape quotes either.
+    let binary = if binary.contains(',') { format!("\"{}\"", binary) } else { binary.to_string() };
+    for (addr, counts) in addrs {
+        csv.push_str(&format!(
+            "{},0x{:x},{},{},{}\n",
+            binary, addr, counts.children, counts.self_period, counts.samples
+        ));
+    }
+    csv
+}
+
+/// Split a csv line into fields, removing the quotes around fields that contain commas.
+fn split_csv_line(line: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                fields.push(unquote(&line[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(unquote(&line[start..]));
+    fields
+}
+
+fn unquote(field: &str) -> &str {
+    field.strip_prefix('"').and_then(|f| f.strip_suffix('"')).unwrap_or(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_dir, write};
+
+    static REPORT: &str = "\
+Cmdline: /system/bin/simpleperf record -e cpu-clock -a -g
+Arch: arm64
+Event: cpu-clock (type 1, config 0)
+Samples: 4
+Event count: 4000
+
+Children,Self,Sample,Shared Object,VaddrInFile,AccEventCount,SelfEventCount,EventName
+3000,1000,1,/system/lib64/libc.so,0x1a2b0,3000,1000,cpu-clock
+2000,2000,2,/system/lib64/libc.so,0x1a2c4,2000,2000,cpu-clock
+1000,1000,1,\"/data/app/a,b/base.apk\",0x400,1000,1000,cpu-clock
+";
+
+    fn counts(children: u64, self_period: u64, samples: u64) -> SampleCounts {
+        SampleCounts { children, self_period, samples }
+    }
+
+    #[test]
+    fn parse_report() {
+        let profile = parse_samples(REPORT).unwrap();
+        assert_eq!(profile.len(), 2);
+        let libc = &profile["/system/lib64/libc.so"];
+        assert_eq!(libc[&0x1a2b0], counts(3000, 1000, 1));
+        assert_eq!(libc[&0x1a2c4], counts(2000, 2000, 2));
+        assert_eq!(profile["/data/app/a,b/base.apk"][&0x400], counts(1000, 1000, 1));
+    }
+
+    #[test]
+    fn parse_malformed_report() {
+        assert!(parse_samples("").is_err());
+        assert!(parse_samples("Cmdline: simpleperf record\n").is_err());
+        let header = "Children,Self,Sample,Shared Object,VaddrInFile\n";
+        assert!(parse_samples(&format!("{}1,1,1,/a.so\n", header)).is_err());
+        assert!(parse_samples(&format!("{}1,1,x,/a.so,0x10\n", header)).is_err());
+        assert!(parse_samples(&format!("{}1,1,1,/a.so,0xzz\n", header)).is_err());
+        assert!(parse_samples(header).unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_by_binary() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        for _ in 0..2 {
+            let report = trace_dir.path().join("report.csv");
+            write(&report, REPORT).unwrap();
+            merge_samples(&report, profile_dir.path()).unwrap();
+            assert!(!report.exists());
+        }
+
+        let mut merged: Vec<_> =
+            read_dir(profile_dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+        merged.sort();
+        assert_eq!(merged.len(), 2);
+        let names: Vec<_> =
+            merged.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert!(names[0].starts_with("merged_base.apk_") && names[0].ends_with(".samples"));
+        assert!(names[1].starts_with("merged_libc.so_") && names[1].ends_with(".samples"));
+
+        let apk = parse_samples(&read_to_string(&merged[0]).unwrap()).unwrap();
+        assert_eq!(apk.len(), 1);
+        assert_eq!(apk["/data/app/a,b/base.apk"][&0x400], counts(2000, 2000, 2));
+        let libc = parse_samples(&read_to_string(&merged[1]).unwrap()).unwrap();
+        assert_eq!(libc.len(), 1);
+        assert_eq!(libc["/system/lib64/libc.so"][&0x1a2b0], counts(6000, 2000, 2));
+        assert_eq!(libc["/system/lib64/libc.so"][&0x1a2c4], counts(4000, 4000, 4));
+    }
+
+    #[test]
+    fn corrupt_merged_profile_discarded() {
+        let trace_dir = tempfile::tempdir().unwrap();
+        let profile_dir = tempfile::tempdir().unwrap();
+        let report = trace_dir.path().join("report.csv");
+        write(&report, REPORT).unwrap();
+        let libc = "/system/lib64/libc.so";
+        let merged_path =
+            get_merged_path(profile_dir.path(), libc, libc.as_bytes(), MERGED_PROFILE_EXTENSION);
+        write(&merged_path, "garbage").unwrap();
+
+        merge_samples(&report, profile_dir.path()).unwrap();
+        let merged = parse_samples(&read_to_string(&merged_path).unwrap()).unwrap();
+        assert_eq!(merged[libc][&0x1a2b0], counts(3000, 1000, 1));
+    }
+}
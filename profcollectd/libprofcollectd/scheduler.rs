@@ -16,32 +16,102 @@
 
 //! ProfCollect tracing scheduler.
 
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
-use std::sync::mpsc::{sync_channel, SyncSender};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender, TryRecvError};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 
+use crate::clock::Clock;
 use crate::config::{Config, PROFILE_OUTPUT_DIR, TRACE_OUTPUT_DIR};
+use crate::policy::{CollectionPolicy, SkipReason};
+use crate::timing::CollectionTiming;
 use crate::trace_provider::{self, TraceProvider};
+use crate::trigger::{
+    get_pressure_triggers, get_property_tag, get_trigger_properties, PressureMonitor,
+    PressureResource, PropertyMonitor, PsiTrigger, SystemPropertyMonitor, TriggerEvent,
+};
 use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use simpleperf_profcollect::BinaryFilter;
+
+/// How long pressure trigger workers wait for a notification before checking for termination.
+const TRIGGER_POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Recent activity of the scheduler, for status reporting.
+#[derive(Clone, Default)]
+pub struct SchedulerHistory {
+    pub last_trace: Option<DateTime<Utc>>,
+    pub next_trace: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    /// Number of traces skipped by the collection policy, by reason.
+    pub skipped: BTreeMap<String, u64>,
+}
+
+impl SchedulerHistory {
+    fn record_error(&mut self, now: DateTime<Utc>, e: &anyhow::Error) {
+        self.last_error = Some(format!("{}: {:#}", now.to_rfc3339(), e));
+    }
+
+    fn record_skip(&mut self, reason: SkipReason) {
+        *self.skipped.entry(reason.to_string()).or_default() += 1;
+    }
+}
 
 pub struct Scheduler {
     /// Signal to terminate the periodic collection worker thread, None if periodic collection is
     /// not scheduled.
     termination_ch: Option<SyncSender<()>>,
+    /// Dropped to terminate the pressure trigger worker threads, one per trigger.
+    trigger_chs: Vec<SyncSender<()>>,
     /// The preferred trace provider for the system.
     trace_provider: Arc<Mutex<dyn TraceProvider + Send>>,
+    /// Shared with the worker threads.
+    history: Arc<Mutex<SchedulerHistory>>,
+    clock: Arc<dyn Clock>,
+    trace_dir: PathBuf,
+    profile_dir: PathBuf,
+    /// Where the collection policy reads the device state from.
+    sysfs_root: PathBuf,
 }
 
 impl Scheduler {
-    pub fn new() -> Result<Self> {
+    pub fn new(clock: Arc<dyn Clock>) -> Result<Self> {
         let p = trace_provider::get_trace_provider()?;
-        Ok(Scheduler { termination_ch: None, trace_provider: p })
+        Ok(Scheduler::with_trace_provider(
+            p,
+            clock,
+            &TRACE_OUTPUT_DIR,
+            &PROFILE_OUTPUT_DIR,
+            Path::new("/"),
+        ))
     }
 
-    fn is_scheduled(&self) -> bool {
+    /// A scheduler using the given trace provider and directories rather than the ones of the
+    /// device.
+    pub fn with_trace_provider(
+        trace_provider: Arc<Mutex<dyn TraceProvider + Send>>,
+        clock: Arc<dyn Clock>,
+        trace_dir: &Path,
+        profile_dir: &Path,
+        sysfs_root: &Path,
+    ) -> Self {
+        Scheduler {
+            termination_ch: None,
+            trigger_chs: Vec::new(),
+            trace_provider,
+            history: Arc::new(Mutex::new(SchedulerHistory::default())),
+            clock,
+            trace_dir: trace_dir.to_path_buf(),
+            profile_dir: profile_dir.to_path_buf(),
+            sysfs_root: sysfs_root.to_path_buf(),
+        }
+    }
+
+    pub fn is_scheduled(&self) -> bool {
         self.termination_ch.is_some()
     }
 
@@ -51,26 +121,53 @@ impl Scheduler {
         let (sender, receiver) = sync_channel(1);
         self.termination_ch = Some(sender);
 
-        // Clone config and trace_provider ARC for the worker thread.
+        // Clone config, trace_provider, history and clock ARC for the worker thread.
         let config = config.clone();
         let trace_provider = self.trace_provider.clone();
+        let history = self.history.clone();
+        let clock = self.clock.clone();
+        let trace_dir = self.trace_dir.clone();
+        let timing = CollectionTiming::new(&config);
+        let policy = CollectionPolicy::new(&config, &self.sysfs_root);
 
         thread::spawn(move || {
+            let mut rng = rand::thread_rng();
+            // Local day of the last periodic trace, and number of periodic traces on that day.
+            let mut daily_traces: Option<(NaiveDate, u32)> = None;
             loop {
-                match receiver.recv_timeout(config.collection_interval) {
-                    Ok(_) => break,
-                    Err(_) => {
-                        // Did not receive a termination signal, initiate trace event.
-                        if check_space_limit(*TRACE_OUTPUT_DIR, &config).unwrap() {
-                            trace_provider.lock().unwrap().trace(
-                                &TRACE_OUTPUT_DIR,
-                                "periodic",
-                                &config.sampling_period,
-                            );
-                        }
-                    }
+                let now = clock.now();
+                let local_now = now.with_timezone(&clock.local_offset(now));
+                let traces_today = match daily_traces {
+                    Some((day, count)) if day == local_now.naive_local().date() => count,
+                    _ => 0,
+                };
+                let next_trace =
+                    timing.next_trace_time(local_now, traces_today, &mut rng).with_timezone(&Utc);
+                history.lock().unwrap().next_trace = Some(next_trace);
+                if clock.sleep((next_trace - now).to_std().unwrap_or_default(), &receiver) {
+                    break;
+                }
+                let now = clock.now();
+                let today = now.with_timezone(&clock.local_offset(now)).naive_local().date();
+                daily_traces = match daily_traces {
+                    Some((day, count)) if day == today => Some((day, count + 1)),
+                    _ => Some((today, 1)),
+                };
+                // Did not receive a termination signal, initiate trace event.
+                let result = trace(
+                    &trace_provider,
+                    &history,
+                    &*clock,
+                    &trace_dir,
+                    &policy,
+                    &config,
+                    "periodic",
+                );
+                if let Err(e) = result {
+                    log::error!("Periodic trace failed: {:?}", e);
                 }
             }
+            history.lock().unwrap().next_trace = None;
         });
         Ok(())
     }
@@ -86,21 +183,163 @@ impl Scheduler {
     }
 
     pub fn one_shot(&self, config: &Config, tag: &str) -> Result<()> {
-        let trace_provider = self.trace_provider.clone();
-        if check_space_limit(*TRACE_OUTPUT_DIR, config)? {
-            trace_provider.lock().unwrap().trace(&TRACE_OUTPUT_DIR, tag, &config.sampling_period);
+        trace(
+            &self.trace_provider,
+            &self.history,
+            &*self.clock,
+            &self.trace_dir,
+            &CollectionPolicy::new(config, &self.sysfs_root),
+            config,
+            tag,
+        )
+        .map(|_| ())
+    }
+
+    /// Start a one-off trace on every pressure spike over the thresholds of `config`, and every
+    /// time one of its trigger properties is set, replacing any triggers started before.
+    /// Pressure triggers the kernel does not support are skipped.
+    pub fn start_triggers(&mut self, config: &Config) {
+        let mut pressure_monitors: Vec<PressureTrigger> = Vec::new();
+        for (resource, threshold) in get_pressure_triggers(config) {
+            match PsiTrigger::new(&self.sysfs_root, resource, threshold) {
+                Ok(trigger) => pressure_monitors.push((resource, threshold, Box::new(trigger))),
+                Err(e) => {
+                    log::error!("Cannot trigger on {} pressure: {:#}", resource.get_name(), e)
+                }
+            }
         }
-        Ok(())
+        let property_monitors = get_trigger_properties(config)
+            .into_iter()
+            .map(|key| (key.to_string(), Box::new(SystemPropertyMonitor::new(key)) as _))
+            .collect();
+        self.start_triggers_with(config, pressure_monitors, property_monitors);
     }
 
-    pub fn process(&self, blocking: bool) -> Result<()> {
+    fn start_triggers_with(
+        &mut self,
+        config: &Config,
+        pressure_monitors: Vec<PressureTrigger>,
+        property_monitors: Vec<(String, Box<dyn PropertyMonitor>)>,
+    ) {
+        self.stop_triggers();
+        // Shared by the pressure triggers, so that the cooldown applies across resources.
+        let last_triggered: Arc<Mutex<Option<DateTime<Utc>>>> = Arc::new(Mutex::new(None));
+        for (resource, threshold, mut monitor) in pressure_monitors {
+            let last_triggered = last_triggered.clone();
+            // Pressure triggers exist to trace under load, which the load limits would prevent.
+            let policy = CollectionPolicy::new(config, &self.sysfs_root).without_load_limits();
+            self.spawn_trigger(config, policy, move |context| {
+                match monitor.wait(TRIGGER_POLL_TIMEOUT) {
+                    Ok(true) => (),
+                    Ok(false) => return true,
+                    Err(e) => {
+                        log::error!("{} pressure trigger failed: {:#}", resource.get_name(), e);
+                        return false;
+                    }
+                }
+                // Held while tracing, so that another resource spiking meanwhile sees this trace.
+                let mut last_triggered = last_triggered.lock().unwrap();
+                let now = context.clock.now();
+                if in_cooldown(*last_triggered, now, context.config.trigger_cooldown) {
+                    log::info!("Ignoring {} pressure spike in cooldown", resource.get_name());
+                    return true;
+                }
+
+                let event = TriggerEvent::new(&context.sysfs_root, resource, threshold, now);
+                let tag = resource.get_tag();
+                let result = context.trace(tag).and_then(|timestamp| match timestamp {
+                    Some(timestamp) => {
+                        // Skipped traces don't start the cooldown.
+                        *last_triggered = Some(timestamp);
+                        event.save(&context.profile_dir, tag, timestamp)
+                    }
+                    None => Ok(()),
+                });
+                if let Err(e) = result {
+                    log::error!("Pressure triggered trace failed: {:?}", e);
+                }
+                true
+            });
+        }
+        for (key, mut monitor) in property_monitors {
+            let policy = CollectionPolicy::new(config, &self.sysfs_root);
+            // Each property has its own worker, and so its own cooldown.
+            let mut last_triggered: Option<DateTime<Utc>> = None;
+            self.spawn_trigger(config, policy, move |context| {
+                let value = match monitor.wait(TRIGGER_POLL_TIMEOUT) {
+                    Some(value) => value,
+                    None => return true,
+                };
+                let tag = match get_property_tag(&value) {
+                    Some(tag) => tag,
+                    None => return true,
+                };
+                let now = context.clock.now();
+                if in_cooldown(last_triggered, now, context.config.trigger_cooldown) {
+                    log::info!("Ignoring property {} set in cooldown", key);
+                    return true;
+                }
+                log::info!("Property {} set, tracing {}", key, tag);
+                match context.trace(&tag) {
+                    Ok(Some(timestamp)) => last_triggered = Some(timestamp),
+                    Ok(None) => (),
+                    Err(e) => log::error!("Property triggered trace failed: {:?}", e),
+                }
+                true
+            });
+        }
+    }
+
+    /// Run `step` on a new worker thread until it returns false or the triggers are stopped.
+    fn spawn_trigger(
+        &mut self,
+        config: &Config,
+        policy: CollectionPolicy,
+        mut step: impl FnMut(&TriggerContext) -> bool + Send + 'static,
+    ) {
+        let (sender, receiver) = sync_channel::<()>(1);
+        self.trigger_chs.push(sender);
+        let context = TriggerContext {
+            config: config.clone(),
+            trace_provider: self.trace_provider.clone(),
+            history: self.history.clone(),
+            clock: self.clock.clone(),
+            trace_dir: self.trace_dir.clone(),
+            profile_dir: self.profile_dir.clone(),
+            sysfs_root: self.sysfs_root.clone(),
+            policy,
+        };
+        thread::spawn(move || {
+            while let Err(TryRecvError::Empty) = receiver.try_recv() {
+                if !step(&context) {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Stop the triggers, once any trace in progress completes.
+    pub fn stop_triggers(&mut self) {
+        self.trigger_chs.clear();
+    }
+
+    pub fn process(&self, config: &Config, blocking: bool) -> Result<()> {
         let trace_provider = self.trace_provider.clone();
+        let binary_filter = BinaryFilter {
+            include: Some(config.binary_filter.clone()).filter(|f| !f.is_empty()),
+            exclude: Some(config.binary_exclude_filter.clone()).filter(|f| !f.is_empty()),
+        };
+        let history = self.history.clone();
+        let clock = self.clock.clone();
+        let trace_dir = self.trace_dir.clone();
+        let profile_dir = self.profile_dir.clone();
         let handle = thread::spawn(move || {
-            trace_provider
-                .lock()
-                .unwrap()
-                .process(&TRACE_OUTPUT_DIR, &PROFILE_OUTPUT_DIR)
-                .expect("Failed to process profiles.");
+            let result =
+                trace_provider.lock().unwrap().process(&trace_dir, &profile_dir, &binary_filter);
+            if let Err(e) = result {
+                history.lock().unwrap().record_error(clock.now(), &e);
+                panic!("Failed to process profiles: {:?}", e);
+            }
         });
         if blocking {
             handle.join().map_err(|_| anyhow!("Profile process thread panicked."))?;
@@ -108,9 +347,92 @@ impl Scheduler {
         Ok(())
     }
 
+    /// The trace provider, locked while tracing and processing.
+    pub fn get_trace_provider(&self) -> Arc<Mutex<dyn TraceProvider + Send>> {
+        self.trace_provider.clone()
+    }
+
     pub fn get_trace_provider_name(&self) -> &'static str {
         self.trace_provider.lock().unwrap().get_name()
     }
+
+    pub fn get_history(&self) -> SchedulerHistory {
+        self.history.lock().unwrap().clone()
+    }
+}
+
+/// A pressure stall monitor, with its resource and threshold.
+type PressureTrigger = (PressureResource, u32, Box<dyn PressureMonitor>);
+
+/// What trigger worker threads trace with.
+struct TriggerContext {
+    config: Config,
+    trace_provider: Arc<Mutex<dyn TraceProvider + Send>>,
+    history: Arc<Mutex<SchedulerHistory>>,
+    clock: Arc<dyn Clock>,
+    trace_dir: PathBuf,
+    profile_dir: PathBuf,
+    sysfs_root: PathBuf,
+    policy: CollectionPolicy,
+}
+
+impl TriggerContext {
+    fn trace(&self, tag: &str) -> Result<Option<DateTime<Utc>>> {
+        trace(
+            &self.trace_provider,
+            &self.history,
+            &*self.clock,
+            &self.trace_dir,
+            &self.policy,
+            &self.config,
+            tag,
+        )
+    }
+}
+
+/// Trace once if allowed by `policy` and space usage is under limit, recording the outcome in
+/// `history`. Returns the capture time of the trace, None if skipped.
+fn trace(
+    trace_provider: &Arc<Mutex<dyn TraceProvider + Send>>,
+    history: &Mutex<SchedulerHistory>,
+    clock: &dyn Clock,
+    trace_dir: &Path,
+    policy: &CollectionPolicy,
+    config: &Config,
+    tag: &str,
+) -> Result<Option<DateTime<Utc>>> {
+    if let Some(reason) = policy.check() {
+        log::info!("Skipping {} trace: {}", tag, reason);
+        history.lock().unwrap().record_skip(reason);
+        return Ok(None);
+    }
+    let now = clock.now();
+    let result = match check_space_limit(trace_dir, config) {
+        Ok(true) => {
+            trace_provider.lock().unwrap().trace(trace_dir, tag, &config.sampling_period, now)
+        }
+        Ok(false) => {
+            // Not an error for the caller, but worth surfacing in the status.
+            history.lock().unwrap().record_error(now, &anyhow!("trace storage exhausted."));
+            return Ok(None);
+        }
+        Err(e) => Err(e),
+    };
+    let mut history = history.lock().unwrap();
+    match &result {
+        Ok(()) => history.last_trace = Some(now),
+        Err(e) => history.record_error(now, e),
+    }
+    result.map(|_| Some(now))
+}
+
+/// Whether `now` is less than `cooldown` after `last`.
+fn in_cooldown(last: Option<DateTime<Utc>>, now: DateTime<Utc>, cooldown: Duration) -> bool {
+    match last.map(|last| (now - last).to_std()) {
+        Some(Ok(elapsed)) => elapsed < cooldown,
+        // Never triggered, or the clock went backwards.
+        _ => false,
+    }
 }
 
 /// Run if space usage is under limit.
@@ -130,3 +452,408 @@ fn dir_size(path: &Path) -> Result<u64> {
         Ok(acc + size)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::logging_trace_provider::LoggingTraceProvider;
+    use chrono::FixedOffset;
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    use tempfile::TempDir;
+
+    const INTERVAL: Duration = Duration::from_secs(600);
+
+    struct Fixture {
+        scheduler: Scheduler,
+        clock: Arc<VirtualClock>,
+        config: Config,
+        trace_dir: TempDir,
+        profile_dir: TempDir,
+        sysfs_root: TempDir,
+    }
+
+    fn start_time() -> DateTime<Utc> {
+        "2021-06-01T12:00:00Z".parse().unwrap()
+    }
+
+    fn setup() -> Fixture {
+        let clock = Arc::new(VirtualClock::new(start_time()));
+        let trace_dir = TempDir::new().unwrap();
+        let profile_dir = TempDir::new().unwrap();
+        let sysfs_root = TempDir::new().unwrap();
+        let scheduler = Scheduler::with_trace_provider(
+            Arc::new(Mutex::new(LoggingTraceProvider {})),
+            clock.clone(),
+            trace_dir.path(),
+            profile_dir.path(),
+            sysfs_root.path(),
+        );
+        let mut config = Config::for_test();
+        config.collection_interval = INTERVAL;
+        Fixture { scheduler, clock, config, trace_dir, profile_dir, sysfs_root }
+    }
+
+    /// Capture time and tag of the traces in `dir`, oldest first.
+    fn list_traces(dir: &TempDir) -> Vec<(DateTime<Utc>, String)> {
+        let mut traces: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| trace_provider::parse_path(&e.unwrap().path()).unwrap())
+            .collect();
+        traces.sort();
+        traces
+    }
+
+    fn at(offset: Duration) -> DateTime<Utc> {
+        start_time() + chrono::Duration::from_std(offset).unwrap()
+    }
+
+    #[test]
+    fn periodic_traces_every_interval() {
+        let mut f = setup();
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        f.clock.wait_for_sleeps(1);
+        assert_eq!(f.scheduler.get_history().next_trace, Some(at(INTERVAL)));
+        assert!(list_traces(&f.trace_dir).is_empty());
+
+        f.clock.advance(INTERVAL - Duration::from_secs(1));
+        f.clock.advance_and_settle(Duration::from_secs(1));
+        assert_eq!(list_traces(&f.trace_dir), vec![(at(INTERVAL), "periodic".to_string())]);
+
+        f.clock.advance_and_settle(INTERVAL);
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![
+                (at(INTERVAL), "periodic".to_string()),
+                (at(INTERVAL * 2), "periodic".to_string())
+            ]
+        );
+        let history = f.scheduler.get_history();
+        assert_eq!(history.last_trace, Some(at(INTERVAL * 2)));
+        assert_eq!(history.next_trace, Some(at(INTERVAL * 3)));
+        assert_eq!(history.last_error, None);
+        f.scheduler.terminate_periodic().unwrap();
+    }
+
+    #[test]
+    fn one_shot_traces_immediately() {
+        let mut f = setup();
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        assert_eq!(list_traces(&f.trace_dir), vec![(start_time(), "manual".to_string())]);
+
+        // One-shot traces don't shift the periodic schedule.
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        f.clock.wait_for_sleeps(1);
+        f.clock.advance(INTERVAL / 2);
+        f.scheduler.one_shot(&f.config, "boot").unwrap();
+        f.clock.advance_and_settle(INTERVAL / 2);
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![
+                (start_time(), "manual".to_string()),
+                (at(INTERVAL / 2), "boot".to_string()),
+                (at(INTERVAL), "periodic".to_string()),
+            ]
+        );
+        assert_eq!(f.scheduler.get_history().last_trace, Some(at(INTERVAL)));
+        f.scheduler.terminate_periodic().unwrap();
+    }
+
+    #[test]
+    fn terminate_stops_periodic_traces() {
+        let mut f = setup();
+        assert!(f.scheduler.terminate_periodic().is_err());
+
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        assert!(f.scheduler.schedule_periodic(&f.config).is_err());
+        f.clock.wait_for_sleeps(1);
+        f.scheduler.terminate_periodic().unwrap();
+        assert!(!f.scheduler.is_scheduled());
+
+        // The terminated worker wakes up before the new one, and must not trace.
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        f.clock.wait_for_sleeps(2);
+        f.clock.advance_and_settle(INTERVAL);
+        assert_eq!(list_traces(&f.trace_dir), vec![(at(INTERVAL), "periodic".to_string())]);
+
+        f.scheduler.terminate_periodic().unwrap();
+        f.clock.advance(INTERVAL * 10);
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![(at(INTERVAL), "periodic".to_string()), (at(INTERVAL * 11), "manual".to_string())]
+        );
+    }
+
+    #[test]
+    fn periodic_traces_stop_at_daily_cap() {
+        let mut f = setup();
+        f.config.collection_interval = Duration::from_secs(4 * 60 * 60);
+        f.config.max_daily_traces = 1;
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        f.clock.wait_for_sleeps(1);
+
+        // Started at noon, the first trace is at 16:00 and the next one waits for midnight.
+        f.clock.advance_and_settle(f.config.collection_interval);
+        let midnight = "2021-06-02T00:00:00Z".parse().unwrap();
+        assert_eq!(f.scheduler.get_history().next_trace, Some(midnight));
+        f.clock.advance_and_settle(Duration::from_secs(8 * 60 * 60));
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![
+                ("2021-06-01T16:00:00Z".parse().unwrap(), "periodic".to_string()),
+                (midnight, "periodic".to_string()),
+            ]
+        );
+        f.scheduler.terminate_periodic().unwrap();
+    }
+
+    #[test]
+    fn daily_cap_counts_local_days() {
+        let mut f = setup();
+        f.clock.set_local_offset(FixedOffset::west_opt(8 * 60 * 60).unwrap());
+        f.config.collection_interval = Duration::from_secs(4 * 60 * 60);
+        f.config.max_daily_traces = 1;
+        f.scheduler.schedule_periodic(&f.config).unwrap();
+        f.clock.wait_for_sleeps(1);
+
+        // The first trace is at 16:00 UTC, 08:00 local, and the next one waits for local midnight.
+        f.clock.advance_and_settle(f.config.collection_interval);
+        assert_eq!(
+            f.scheduler.get_history().next_trace,
+            Some("2021-06-02T08:00:00Z".parse().unwrap())
+        );
+        f.scheduler.terminate_periodic().unwrap();
+    }
+
+    #[test]
+    fn trace_skipped_by_policy() {
+        let mut f = setup();
+        let battery = f.sysfs_root.path().join("sys/class/power_supply/battery");
+        fs::create_dir_all(&battery).unwrap();
+        fs::write(battery.join("type"), "Battery\n").unwrap();
+        fs::write(battery.join("status"), "Discharging\n").unwrap();
+        fs::write(battery.join("capacity"), "80\n").unwrap();
+        f.config.require_charging = true;
+
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        assert!(list_traces(&f.trace_dir).is_empty());
+
+        fs::write(battery.join("status"), "Charging\n").unwrap();
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        assert_eq!(list_traces(&f.trace_dir).len(), 1);
+        let history = f.scheduler.get_history();
+        assert_eq!(history.skipped.get("not_charging"), Some(&2));
+        assert_eq!(history.last_error, None);
+    }
+
+    /// Notifications sent by the test, each acknowledged once handled.
+    struct FakeMonitor<T> {
+        notifications: Receiver<(T, SyncSender<()>)>,
+        pending_ack: Option<SyncSender<()>>,
+        /// Disconnects the receiver held by the test once the monitor is dropped.
+        _alive: SyncSender<()>,
+    }
+
+    impl<T> FakeMonitor<T> {
+        fn next(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+            if let Some(ack) = self.pending_ack.take() {
+                ack.send(()).ok();
+            }
+            let (notification, ack) = self.notifications.recv_timeout(timeout)?;
+            self.pending_ack = Some(ack);
+            Ok(notification)
+        }
+    }
+
+    impl PressureMonitor for FakeMonitor<()> {
+        fn wait(&mut self, timeout: Duration) -> Result<bool> {
+            match self.next(timeout) {
+                Ok(()) => Ok(true),
+                Err(RecvTimeoutError::Timeout) => Ok(false),
+                Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Monitor closed")),
+            }
+        }
+    }
+
+    impl PropertyMonitor for FakeMonitor<String> {
+        fn wait(&mut self, timeout: Duration) -> Option<String> {
+            self.next(timeout).ok()
+        }
+    }
+
+    struct FakeMonitorHandle<T> {
+        notifications: SyncSender<(T, SyncSender<()>)>,
+        alive: Receiver<()>,
+    }
+
+    impl<T> FakeMonitorHandle<T> {
+        /// Notify the trigger, and wait for it to handle the notification.
+        fn notify(&self, notification: T) {
+            let (ack, acked) = sync_channel(1);
+            assert!(self.notifications.send((notification, ack)).is_ok(), "Trigger worker exited");
+            acked.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+
+        /// Wait for the trigger worker to drop its monitor.
+        fn wait_for_drop(&self) {
+            let result = self.alive.recv_timeout(Duration::from_secs(10));
+            assert_eq!(result, Err(RecvTimeoutError::Disconnected));
+        }
+    }
+
+    fn fake_monitor<T>() -> (FakeMonitorHandle<T>, FakeMonitor<T>) {
+        let (notifications, receiver) = sync_channel(0);
+        let (alive, alive_receiver) = sync_channel(0);
+        let monitor = FakeMonitor { notifications: receiver, pending_ack: None, _alive: alive };
+        (FakeMonitorHandle { notifications, alive: alive_receiver }, monitor)
+    }
+
+    #[test]
+    fn pressure_triggers_trace_with_cooldown() {
+        let mut f = setup();
+        let psi = f.sysfs_root.path().join("proc/pressure");
+        fs::create_dir_all(&psi).unwrap();
+        fs::write(psi.join("io"), "some avg10=42.00 avg60=10.00 avg300=2.00 total=123456\n")
+            .unwrap();
+        f.config.trigger_cooldown = INTERVAL;
+        let (cpu, cpu_monitor) = fake_monitor();
+        let (io, io_monitor) = fake_monitor();
+        f.scheduler.start_triggers_with(
+            &f.config,
+            vec![
+                (PressureResource::Cpu, 20, Box::new(cpu_monitor)),
+                (PressureResource::Io, 30, Box::new(io_monitor)),
+            ],
+            Vec::new(),
+        );
+
+        io.notify(());
+        f.clock.advance(INTERVAL / 2);
+        cpu.notify(());
+        f.clock.advance(INTERVAL / 2);
+        cpu.notify(());
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![(start_time(), "psi_io".to_string()), (at(INTERVAL), "psi_cpu".to_string())]
+        );
+
+        let event = |name| {
+            let event = fs::read_to_string(f.profile_dir.path().join(name)).unwrap();
+            serde_json::from_str::<serde_json::Value>(&event).unwrap()
+        };
+        assert_eq!(
+            event("20210601-120000_psi_io.trigger"),
+            serde_json::json!({
+                "resource": "io",
+                "threshold": 30,
+                "window_ms": 2000,
+                "triggered": start_time().to_rfc3339(),
+                "avg10": 42.0,
+            })
+        );
+        assert_eq!(event("20210601-121000_psi_cpu.trigger")["avg10"], serde_json::Value::Null);
+
+        // The workers exit within TRIGGER_POLL_TIMEOUT, dropping their monitors.
+        f.scheduler.stop_triggers();
+        cpu.wait_for_drop();
+        io.wait_for_drop();
+    }
+
+    #[test]
+    fn pressure_triggers_ignore_load_limits() {
+        let mut f = setup();
+        let sysfs_root = f.sysfs_root.path().to_path_buf();
+        let write = |path: &str, contents: &str| {
+            let path = sysfs_root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+        write("proc/pressure/cpu", "some avg10=90.00 avg60=50.00 avg300=10.00 total=123456\n");
+        write("proc/loadavg", "12.00 8.00 4.00 3/456 7890\n");
+        write("sys/class/power_supply/battery/type", "Battery\n");
+        write("sys/class/power_supply/battery/status", "Discharging\n");
+        write("sys/class/power_supply/battery/capacity", "80\n");
+        f.config.max_cpu_pressure = 10;
+        f.config.max_load_average = 4;
+        f.config.require_charging = true;
+        f.config.trigger_cooldown = INTERVAL;
+        let (cpu, cpu_monitor) = fake_monitor();
+        f.scheduler.start_triggers_with(
+            &f.config,
+            vec![(PressureResource::Cpu, 20, Box::new(cpu_monitor))],
+            Vec::new(),
+        );
+
+        cpu.notify(());
+        assert!(list_traces(&f.trace_dir).is_empty());
+        // The skipped trace did not start the cooldown.
+        write("sys/class/power_supply/battery/status", "Charging\n");
+        f.clock.advance(Duration::from_secs(1));
+        cpu.notify(());
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![(at(Duration::from_secs(1)), "psi_cpu".to_string())]
+        );
+        assert_eq!(f.scheduler.get_history().skipped.get("not_charging"), Some(&1));
+
+        f.scheduler.stop_triggers();
+        cpu.wait_for_drop();
+    }
+
+    #[test]
+    fn property_triggers_trace_tagged_with_value() {
+        let mut f = setup();
+        f.config.trigger_cooldown = INTERVAL;
+        let (property, monitor) = fake_monitor();
+        let (other_property, other_monitor) = fake_monitor();
+        f.scheduler.start_triggers_with(
+            &f.config,
+            Vec::new(),
+            vec![
+                ("sys.profcollectd.trigger".to_string(), Box::new(monitor)),
+                ("sys.profcollectd.other".to_string(), Box::new(other_monitor)),
+            ],
+        );
+
+        property.notify("boot_complete".to_string());
+        f.clock.advance(Duration::from_secs(1));
+        // In cooldown for this property only, and cleared properties don't trace.
+        property.notify("com.example/.Main".to_string());
+        other_property.notify("com.example/.Main".to_string());
+        f.clock.advance(INTERVAL);
+        property.notify("".to_string());
+        property.notify("com.example/.Main".to_string());
+        let later = at(INTERVAL + Duration::from_secs(1));
+        assert_eq!(
+            list_traces(&f.trace_dir),
+            vec![
+                (start_time(), "boot_complete".to_string()),
+                (at(Duration::from_secs(1)), "com_example__Main".to_string()),
+                (later, "com_example__Main".to_string()),
+            ]
+        );
+
+        // Restarting the triggers replaces the workers.
+        f.scheduler.start_triggers_with(&f.config, Vec::new(), Vec::new());
+        property.wait_for_drop();
+        other_property.wait_for_drop();
+    }
+
+    #[test]
+    fn trace_skipped_when_storage_exhausted() {
+        let mut f = setup();
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        fs::write(f.trace_dir.path().join("20210601-115959_filler.loggingtrace"), [0u8; 16])
+            .unwrap();
+        f.config.max_trace_limit = 8;
+
+        f.clock.advance(INTERVAL);
+        f.scheduler.one_shot(&f.config, "manual").unwrap();
+        assert_eq!(list_traces(&f.trace_dir).len(), 2);
+        let history = f.scheduler.get_history();
+        assert_eq!(history.last_trace, Some(start_time()));
+        assert!(history.last_error.unwrap().contains("trace storage exhausted"));
+    }
+}
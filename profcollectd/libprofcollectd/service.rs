@@ -16,23 +16,32 @@
 
 //! ProfCollect Binder service implementation.
 
-use anyhow::{anyhow, bail, Context, Error, Result};
+use anyhow::{bail, ensure, Context, Error, Result};
+use binder::public_api::ParcelFileDescriptor;
 use binder::public_api::Result as BinderResult;
 use binder::Status;
-use profcollectd_aidl_interface::aidl::com::android::server::profcollect::IProfCollectd::IProfCollectd;
+use ed25519_dalek::Keypair;
+use profcollectd_aidl_interface::aidl::com::android::server::profcollect::IProfCollectd;
 use std::ffi::CString;
-use std::fs::{copy, read_dir, read_to_string, remove_file, write};
+use std::fs::{read_dir, read_to_string, remove_file, write};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
+use crate::clock::{Clock, SystemClock};
 use crate::config::{
-    clear_data, Config, BETTERBUG_CACHE_DIR_PREFIX, BETTERBUG_CACHE_DIR_SUFFIX, CONFIG_FILE,
-    PROFILE_OUTPUT_DIR, REPORT_OUTPUT_DIR, REPORT_RETENTION_SECS,
+    clear_data, Config, CONFIG_FILE, PROFILE_OUTPUT_DIR, REPORT_INDEX_FILE, REPORT_OUTPUT_DIR,
+    REPORT_RETENTION_SECS, SIGNING_KEY_FILE,
 };
-use crate::report::{get_report_ts, pack_report};
+use crate::report::{get_report_ts, pack_report, TMP_REPORT_EXTENSION};
+use crate::report_index::ReportIndex;
+use crate::report_info::{inspect_report, list_reports};
+use crate::report_sink::{parse_report_sinks, DirectorySink, FileDescriptorSink, ReportSink};
 use crate::scheduler::Scheduler;
+use crate::signing::get_or_create_signing_key;
+use crate::status::Status as ServiceStatus;
+use crate::uploader::Uploader;
 
 fn err_to_binder_status(msg: Error) -> Status {
     let msg = format!("{:#?}", msg);
@@ -42,26 +51,35 @@ fn err_to_binder_status(msg: Error) -> Status {
 
 pub struct ProfcollectdBinderService {
     lock: Mutex<Lock>,
+    /// Serialises report packing and updates to the report index, without blocking the scheduler.
+    report_lock: Arc<Mutex<()>>,
+    signing_key: Keypair,
+    clock: Arc<dyn Clock>,
 }
 
 struct Lock {
     config: Config,
     scheduler: Scheduler,
+    /// None if uploads are disabled.
+    uploader: Option<Uploader>,
 }
 
 impl binder::Interface for ProfcollectdBinderService {}
 
-impl IProfCollectd for ProfcollectdBinderService {
+impl IProfCollectd::IProfCollectd for ProfcollectdBinderService {
     fn schedule(&self) -> BinderResult<()> {
         let lock = &mut *self.lock();
         lock.scheduler
             .schedule_periodic(&lock.config)
             .context("Failed to schedule collection.")
-            .map_err(err_to_binder_status)
+            .map_err(err_to_binder_status)?;
+        lock.scheduler.start_triggers(&lock.config);
+        Ok(())
     }
     fn terminate(&self) -> BinderResult<()> {
-        self.lock()
-            .scheduler
+        let lock = &mut *self.lock();
+        lock.scheduler.stop_triggers();
+        lock.scheduler
             .terminate_periodic()
             .context("Failed to terminate collection.")
             .map_err(err_to_binder_status)
@@ -76,17 +94,94 @@ impl IProfCollectd for ProfcollectdBinderService {
     fn process(&self, blocking: bool) -> BinderResult<()> {
         let lock = &mut *self.lock();
         lock.scheduler
-            .process(blocking)
+            .process(&lock.config, blocking)
             .context("Failed to process profiles.")
             .map_err(err_to_binder_status)
     }
-    fn report(&self) -> BinderResult<String> {
-        self.process(true)?;
+    fn reconfigure(&self) -> BinderResult<()> {
+        let old_uploader = {
+            let lock = &mut *self.lock();
+            let new_config = Config::from_env()
+                .context("Failed to read configuration.")
+                .map_err(err_to_binder_status)?;
+            if new_config == lock.config {
+                return Ok(());
+            }
 
+            log::info!("Config change detected, reconfiguring profcollect.");
+            apply_config(&lock.config, &new_config).map_err(err_to_binder_status)?;
+            if lock.scheduler.is_scheduled() {
+                // Restart the periodic worker and triggers to pick up the new interval,
+                // thresholds and sampling period.
+                lock.scheduler
+                    .terminate_periodic()
+                    .and_then(|_| lock.scheduler.schedule_periodic(&new_config))
+                    .context("Failed to reschedule collection.")
+                    .map_err(err_to_binder_status)?;
+                lock.scheduler.start_triggers(&new_config);
+            }
+            let restart_uploads = (&new_config.upload_url, new_config.upload_daily_budget)
+                != (&lock.config.upload_url, lock.config.upload_daily_budget);
+            lock.config = new_config;
+            if !restart_uploads {
+                return Ok(());
+            }
+            // Stop the previous uploader, but wait for any upload in progress to be abandoned
+            // without holding the lock.
+            let old_uploader = lock.uploader.take();
+            if let Some(uploader) = &old_uploader {
+                uploader.stop();
+            }
+            old_uploader
+        };
+        drop(old_uploader);
+
+        // The previous uploader is gone, so the new one has its state to itself.
         let lock = &mut *self.lock();
-        pack_report(&PROFILE_OUTPUT_DIR, &REPORT_OUTPUT_DIR, &lock.config)
-            .context("Failed to create profile report.")
-            .map_err(err_to_binder_status)
+        if lock.uploader.is_none() {
+            lock.uploader = self
+                .start_uploader(&lock.config)
+                .context("Failed to restart uploads.")
+                .map_err(err_to_binder_status)?;
+        }
+        Ok(())
+    }
+    fn report(&self, full: bool) -> BinderResult<Vec<String>> {
+        self.process(true)?;
+
+        // Don't hold the service lock while packing, which may take a while.
+        let (config, trace_provider) = {
+            let lock = &*self.lock();
+            (lock.config.clone(), lock.scheduler.get_trace_provider())
+        };
+
+        let report_lock = self.report_lock.lock().unwrap();
+        // Keep traces from being merged into the profiles being packed until they are sealed.
+        let trace_provider = trace_provider.lock().unwrap();
+        let mut index = ReportIndex::load(&REPORT_INDEX_FILE);
+        let report_names = pack_report(
+            &PROFILE_OUTPUT_DIR,
+            &REPORT_OUTPUT_DIR,
+            &config,
+            trace_provider.get_name(),
+            &self.signing_key,
+            &*self.clock,
+            &mut index,
+            full,
+        )
+        .and_then(|report_names| {
+            index.save(&REPORT_INDEX_FILE)?;
+            Ok(report_names)
+        })
+        .context("Failed to create profile report.")
+        .map_err(err_to_binder_status)?;
+
+        drop(trace_provider);
+        drop(report_lock);
+        if let Some(uploader) = &self.lock().uploader {
+            uploader.wake();
+        }
+        Ok(report_names)
     }
     fn delete_report(&self, report_name: &str) -> BinderResult<()> {
         verify_report_name(&report_name).map_err(err_to_binder_status)?;
@@ -95,35 +190,62 @@ impl IProfCollectd for ProfcollectdBinderService {
         report.push(report_name);
         report.set_extension("zip");
         remove_file(&report).ok();
-        Ok(())
+
+        remove_reported_profiles(&self.report_lock, report_name)
+            .context("Failed to remove reported profiles.")
+            .map_err(err_to_binder_status)
     }
     fn copy_report_to_bb(&self, bb_profile_id: i32, report_name: &str) -> BinderResult<()> {
-        if bb_profile_id < 0 {
-            return Err(err_to_binder_status(anyhow!("Invalid profile ID")));
-        }
-        verify_report_name(&report_name).map_err(err_to_binder_status)?;
-
-        let mut report = PathBuf::from(&*REPORT_OUTPUT_DIR);
-        report.push(report_name);
-        report.set_extension("zip");
-
-        let mut dest = PathBuf::from(&*BETTERBUG_CACHE_DIR_PREFIX);
-        dest.push(bb_profile_id.to_string());
-        dest.push(&*BETTERBUG_CACHE_DIR_SUFFIX);
-        if !dest.is_dir() {
-            return Err(err_to_binder_status(anyhow!("Cannot open BetterBug cache dir")));
-        }
-        dest.push(report_name);
-        dest.set_extension("zip");
-
-        copy(report, dest)
-            .map(|_| ())
+        DirectorySink::betterbug(bb_profile_id)
+            .and_then(|sink| self.export(report_name, &[Box::new(sink)]))
             .context("Failed to copy report to bb storage.")
             .map_err(err_to_binder_status)
     }
+    fn export_report(&self, report_name: &str) -> BinderResult<()> {
+        let report_sinks = self.lock().config.report_sinks.clone();
+        parse_report_sinks(&report_sinks)
+            .and_then(|sinks| {
+                ensure!(!sinks.is_empty(), "No report sink configured");
+                self.export(report_name, &sinks)
+            })
+            .context("Failed to export report.")
+            .map_err(err_to_binder_status)
+    }
+    fn export_report_to_fd(
+        &self,
+        report_name: &str,
+        fd: &ParcelFileDescriptor,
+    ) -> BinderResult<()> {
+        fd.as_ref()
+            .try_clone()
+            .map_err(Error::from)
+            .and_then(|file| self.export(report_name, &[Box::new(FileDescriptorSink::new(file))]))
+            .context("Failed to write report to file descriptor.")
+            .map_err(err_to_binder_status)
+    }
+    fn list_reports(&self) -> BinderResult<String> {
+        list_reports(&REPORT_OUTPUT_DIR)
+            .and_then(|reports| Ok(serde_json::to_string_pretty(&reports)?))
+            .context("Failed to list reports.")
+            .map_err(err_to_binder_status)
+    }
+    fn inspect_report(&self, report_name: &str) -> BinderResult<String> {
+        verify_report_name(report_name)
+            .and_then(|_| inspect_report(&REPORT_OUTPUT_DIR, report_name))
+            .map(|contents| contents.to_string())
+            .context("Failed to inspect report.")
+            .map_err(err_to_binder_status)
+    }
     fn get_supported_provider(&self) -> BinderResult<String> {
         Ok(self.lock().scheduler.get_trace_provider_name().to_string())
     }
+    fn get_status(&self) -> BinderResult<String> {
+        let lock = &*self.lock();
+        ServiceStatus::new(&lock.scheduler, &lock.config)
+            .map(|s| s.to_string())
+            .context("Failed to get status.")
+            .map_err(err_to_binder_status)
+    }
 }
 
 /// Verify that the report name is valid, i.e. not a relative path component, to prevent potential
@@ -135,36 +257,51 @@ fn verify_report_name(report_name: &str) -> Result<()> {
     }
 }
 
+/// Persist `new_config`, clearing local data if it invalidates what was collected under
+/// `old_config`.
+fn apply_config(old_config: &Config, new_config: &Config) -> Result<()> {
+    if old_config.requires_data_reset(new_config) {
+        log::info!("Collected data is incompatible with the new config, resetting profcollect.");
+        clear_data()?;
+    }
+    write(*CONFIG_FILE, &new_config.to_string())?;
+    Ok(())
+}
+
 impl ProfcollectdBinderService {
     pub fn new() -> Result<Self> {
-        let new_scheduler = Scheduler::new()?;
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let new_scheduler = Scheduler::new(clock.clone())?;
         let new_config = Config::from_env()?;
 
-        let config_changed = read_to_string(*CONFIG_FILE)
-            .ok()
-            .and_then(|s| Config::from_str(&s).ok())
-            .filter(|c| new_config == *c)
-            .is_none();
+        let old_config = read_to_string(*CONFIG_FILE).ok().and_then(|s| Config::from_str(&s).ok());
 
-        if config_changed {
-            log::info!("Config change detected, resetting profcollect.");
-            clear_data()?;
-
-            write(*CONFIG_FILE, &new_config.to_string())?;
+        match old_config {
+            Some(old_config) if old_config == new_config => (),
+            Some(old_config) => {
+                log::info!("Config change detected, updating profcollect.");
+                apply_config(&old_config, &new_config)?;
+            }
+            None => {
+                log::info!("No valid config found, resetting profcollect.");
+                clear_data()?;
+                write(*CONFIG_FILE, &new_config.to_string())?;
+            }
         }
 
         // Clear profile reports out of rentention period.
         for report in read_dir(*REPORT_OUTPUT_DIR)? {
             let report = report?.path();
-            let report_name = report
-                .file_stem()
-                .and_then(|f| f.to_str())
-                .ok_or_else(|| anyhow!("Malformed path {}", report.display()))?;
-            let report_ts = get_report_ts(report_name);
+            if report.extension().and_then(|e| e.to_str()) == Some(TMP_REPORT_EXTENSION) {
+                log::info!("Removing incomplete report {}", report.display());
+                remove_file(report)?;
+                continue;
+            }
+            let report_ts = get_report_ts(&report);
             if let Err(e) = report_ts {
                 log::error!(
                     "Cannot decode creation timestamp for report {}, caused by {}, deleting",
-                    report_name,
+                    report.display(),
                     e
                 );
                 remove_file(report)?;
@@ -172,17 +309,65 @@ impl ProfcollectdBinderService {
             }
             let report_age = report_ts.unwrap().elapsed()?;
             if report_age > Duration::from_secs(REPORT_RETENTION_SECS) {
-                log::info!("Report {} past rentention period, deleting", report_name);
+                log::info!("Report {} past rentention period, deleting", report.display());
                 remove_file(report)?;
             }
         }
 
-        Ok(ProfcollectdBinderService {
-            lock: Mutex::new(Lock { scheduler: new_scheduler, config: new_config }),
-        })
+        let mut index = ReportIndex::load(&REPORT_INDEX_FILE);
+        index.retain_reports(&REPORT_OUTPUT_DIR);
+        index.save(&REPORT_INDEX_FILE)?;
+
+        let service = ProfcollectdBinderService {
+            lock: Mutex::new(Lock { scheduler: new_scheduler, config: new_config, uploader: None }),
+            report_lock: Arc::new(Mutex::new(())),
+            signing_key: get_or_create_signing_key(&SIGNING_KEY_FILE)?,
+            clock,
+        };
+        {
+            let lock = &mut *service.lock();
+            // Uploads are optional, don't fail to start over a bad endpoint.
+            lock.uploader = service.start_uploader(&lock.config).unwrap_or_else(|e| {
+                log::error!("Failed to start uploads: {:?}", e);
+                None
+            });
+        }
+        Ok(service)
     }
 
     fn lock(&self) -> MutexGuard<Lock> {
         self.lock.lock().unwrap()
     }
+
+    /// Export `report_name` to every sink, then garbage collect its profiles.
+    fn export(&self, report_name: &str, sinks: &[Box<dyn ReportSink>]) -> Result<()> {
+        verify_report_name(report_name)?;
+        let mut report = PathBuf::from(&*REPORT_OUTPUT_DIR);
+        report.push(report_name);
+        report.set_extension("zip");
+        ensure!(report.is_file(), "No such report: {}", report_name);
+
+        for sink in sinks {
+            sink.export(&report, report_name)
+                .with_context(|| format!("Failed to export to {} sink", sink.get_name()))?;
+        }
+        remove_reported_profiles(&self.report_lock, report_name)
+            .context("Failed to remove reported profiles.")
+    }
+
+    fn start_uploader(&self, config: &Config) -> Result<Option<Uploader>> {
+        let report_lock = self.report_lock.clone();
+        Uploader::start(
+            config,
+            Box::new(move |report_name| remove_reported_profiles(&report_lock, report_name)),
+        )
+    }
+}
+
+/// Garbage collect the profiles packed into `report_name`, once it has been delivered.
+fn remove_reported_profiles(report_lock: &Mutex<()>, report_name: &str) -> Result<()> {
+    let _report_lock = report_lock.lock().unwrap();
+    let mut index = ReportIndex::load(&REPORT_INDEX_FILE);
+    index.remove_reported(report_name, &PROFILE_OUTPUT_DIR)?;
+    index.save(&REPORT_INDEX_FILE)
 }
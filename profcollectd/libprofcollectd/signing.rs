@@ -0,0 +1,299 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Report signing with a per-device Ed25519 key. Each report holds signature.json, signing the
+//! SHA-256 of manifest.json, which in turn lists the SHA-256 of every other file in the report.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zip::ZipArchive;
+
+use crate::encryption::ENVELOPE_MAGIC;
+use crate::manifest::{from_hex, to_hex, Manifest, MANIFEST_FILENAME};
+
+pub const SIGNATURE_FILENAME: &str = "signature.json";
+const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Stored as signature.json in each report.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReportSignature {
+    /// Always "ed25519".
+    pub algorithm: String,
+    /// Hex encoded public key of the device.
+    pub public_key: String,
+    /// Hex encoded SHA-256 of manifest.json.
+    pub manifest_sha256: String,
+    /// Hex encoded signature of the manifest digest.
+    pub signature: String,
+}
+
+impl ReportSignature {
+    pub fn new(signing_key: &Keypair, manifest: &[u8]) -> Self {
+        let digest = Sha256::digest(manifest);
+        ReportSignature {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            public_key: to_hex(signing_key.public.as_bytes()),
+            manifest_sha256: to_hex(&digest),
+            signature: to_hex(&signing_key.sign(&digest).to_bytes()),
+        }
+    }
+}
+
+impl ToString for ReportSignature {
+    fn to_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialise signature.")
+    }
+}
+
+/// Load the device signing key, generating it on first use. The key is kept across data resets,
+/// like the node id.
+pub fn get_or_create_signing_key(path: &Path) -> Result<Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => match SecretKey::from_bytes(&bytes) {
+            Ok(secret) => return Ok(Keypair { public: PublicKey::from(&secret), secret }),
+            Err(e) => log::error!("Discarding invalid signing key {}: {}", path.display(), e),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e).context("Failed to read signing key."),
+    }
+
+    log::info!("Generating a new report signing key");
+    let mut bytes = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = SecretKey::from_bytes(&bytes)?;
+
+    let tmp_path = path.with_extension("tmp");
+    fs::remove_file(&tmp_path).ok();
+    let mut file =
+        fs::OpenOptions::new().create_new(true).write(true).mode(0o600).open(&tmp_path)?;
+    file.write_all(secret.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(Keypair { public: PublicKey::from(&secret), secret })
+}
+
+fn read_entry(zip: &mut ZipArchive<File>, name: &str) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    zip.by_name(name).with_context(|| format!("Missing {}", name))?.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Verify that the report zip `report` is signed and that its contents match its manifest.
+/// Encrypted reports must be decrypted first. Returns the hex encoded public key of the signer,
+/// which callers should check against the key they expect for the device.
+pub fn verify_report(report: &Path) -> Result<String> {
+    let mut file = File::open(report)?;
+    let mut magic = [0u8; 8];
+    if file.read_exact(&mut magic).is_ok() && &magic == ENVELOPE_MAGIC {
+        bail!("Report is encrypted, decrypt it first");
+    }
+    let mut zip = ZipArchive::new(File::open(report)?).context("Not a valid report")?;
+
+    let manifest = read_entry(&mut zip, MANIFEST_FILENAME)?;
+    let signature: ReportSignature =
+        serde_json::from_slice(&read_entry(&mut zip, SIGNATURE_FILENAME)?)
+            .context("Malformed signature")?;
+    ensure!(
+        signature.algorithm == SIGNATURE_ALGORITHM,
+        "Unsupported signature algorithm {}",
+        signature.algorithm
+    );
+    let digest = Sha256::digest(&manifest);
+    ensure!(to_hex(&digest) == signature.manifest_sha256, "Manifest digest mismatch");
+    let public_key = PublicKey::from_bytes(&from_hex(&signature.public_key)?)
+        .map_err(|e| anyhow!("Invalid public key: {}", e))?;
+    let sig = Signature::try_from(&from_hex(&signature.signature)?[..])
+        .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+    public_key.verify_strict(&digest, &sig).map_err(|_| anyhow!("Bad signature"))?;
+
+    // The manifest is authentic, check that the report holds exactly the files it lists.
+    let manifest: Manifest = serde_json::from_slice(&manifest).context("Malformed manifest")?;
+    let mut unlisted: BTreeSet<String> = zip.file_names().map(String::from).collect();
+    unlisted.remove(MANIFEST_FILENAME);
+    unlisted.remove(SIGNATURE_FILENAME);
+    let mut buffer = vec![0u8; 64 * 1024];
+    for entry in &manifest.files {
+        ensure!(unlisted.remove(&entry.name), "Missing file {}", entry.name);
+        let mut f = zip.by_name(&entry.name)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            let len = match f.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&buffer[..len]);
+            size += len as u64;
+        }
+        ensure!(
+            size == entry.size && to_hex(&hasher.finalize()) == entry.sha256,
+            "Contents of {} don't match the manifest",
+            entry.name
+        );
+    }
+    if let Some(name) = unlisted.iter().next() {
+        bail!("Unexpected file {}", name);
+    }
+    Ok(signature.public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use chrono::{DateTime, Utc};
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        Keypair { public: PublicKey::from(&secret), secret }
+    }
+
+    fn manifest(files: &[(&str, &[u8])]) -> String {
+        let created = "2021-06-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let mut manifest = Manifest::new(&Config::for_test(), "test", created, "set", 1, 1);
+        for (name, contents) in files {
+            manifest.add_file(name, contents.len() as u64, &Sha256::digest(contents));
+        }
+        manifest.to_string()
+    }
+
+    /// Write a report holding `files`, with `manifest` and `signature`.
+    fn write_report(
+        dir: &TempDir,
+        files: &[(&str, &[u8])],
+        manifest: &str,
+        signature: &str,
+    ) -> PathBuf {
+        let report = dir.path().join("report.zip");
+        let mut zip = ZipWriter::new(File::create(&report).unwrap());
+        for (name, contents) in files.iter().chain(&[
+            (MANIFEST_FILENAME, manifest.as_bytes()),
+            (SIGNATURE_FILENAME, signature.as_bytes()),
+        ]) {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+        report
+    }
+
+    const FILES: &[(&str, &[u8])] = &[("a.data", b"profile a"), ("b.data", b"profile b")];
+
+    fn signed_report(dir: &TempDir, files: &[(&str, &[u8])], listed: &[(&str, &[u8])]) -> PathBuf {
+        let manifest = manifest(listed);
+        let signature = ReportSignature::new(&keypair(1), manifest.as_bytes()).to_string();
+        write_report(dir, files, &manifest, &signature)
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let dir = TempDir::new().unwrap();
+        let report = signed_report(&dir, FILES, FILES);
+        assert_eq!(verify_report(&report).unwrap(), to_hex(keypair(1).public.as_bytes()));
+    }
+
+    #[test]
+    fn reject_modified_file() {
+        let dir = TempDir::new().unwrap();
+        let report = signed_report(&dir, &[FILES[0], ("b.data", b"profile c")], FILES);
+        assert!(verify_report(&report).is_err());
+        let report = signed_report(&dir, &[FILES[0], ("b.data", b"profile")], FILES);
+        assert!(verify_report(&report).is_err());
+    }
+
+    #[test]
+    fn reject_extra_or_missing_file() {
+        let dir = TempDir::new().unwrap();
+        let report = signed_report(&dir, FILES, &FILES[..1]);
+        assert_eq!(verify_report(&report).unwrap_err().to_string(), "Unexpected file b.data");
+        let report = signed_report(&dir, &FILES[..1], FILES);
+        assert_eq!(verify_report(&report).unwrap_err().to_string(), "Missing file b.data");
+    }
+
+    #[test]
+    fn reject_bad_signature() {
+        let dir = TempDir::new().unwrap();
+        let manifest = manifest(FILES);
+        let verify = |signature: &ReportSignature, manifest: &str| {
+            verify_report(&write_report(&dir, FILES, manifest, &signature.to_string()))
+        };
+
+        // The manifest changed after it was signed, whether or not its digest was updated.
+        let tampered = manifest.replace("\"set\"", "\"other\"");
+        assert_ne!(tampered, manifest);
+        let signature = ReportSignature::new(&keypair(1), manifest.as_bytes());
+        let e = verify(&signature, &tampered).unwrap_err();
+        assert_eq!(e.to_string(), "Manifest digest mismatch");
+        let mut forged = ReportSignature::new(&keypair(1), tampered.as_bytes());
+        forged.signature = signature.signature.clone();
+        assert_eq!(verify(&forged, &tampered).unwrap_err().to_string(), "Bad signature");
+
+        // A corrupted signature.
+        let mut corrupted = ReportSignature::new(&keypair(1), manifest.as_bytes());
+        let last = if corrupted.signature.ends_with('0') { "1" } else { "0" };
+        corrupted.signature.replace_range(corrupted.signature.len() - 1.., last);
+        assert!(verify(&corrupted, &manifest).is_err());
+
+        // Signed with another key than the one claimed.
+        let mut wrong_key = ReportSignature::new(&keypair(2), manifest.as_bytes());
+        wrong_key.public_key = to_hex(keypair(1).public.as_bytes());
+        assert_eq!(verify(&wrong_key, &manifest).unwrap_err().to_string(), "Bad signature");
+
+        let mut algorithm = ReportSignature::new(&keypair(1), manifest.as_bytes());
+        algorithm.algorithm = "rsa".to_string();
+        assert!(verify(&algorithm, &manifest).is_err());
+    }
+
+    #[test]
+    fn reject_encrypted_report() {
+        let dir = TempDir::new().unwrap();
+        let report = dir.path().join("report.zip");
+        fs::write(&report, [&ENVELOPE_MAGIC[..], &[1, 1]].concat()).unwrap();
+        assert_eq!(
+            verify_report(&report).unwrap_err().to_string(),
+            "Report is encrypted, decrypt it first"
+        );
+    }
+
+    #[test]
+    fn signing_key_persisted() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("signing_key");
+        let key = get_or_create_signing_key(&path).unwrap();
+        assert_eq!(get_or_create_signing_key(&path).unwrap().to_bytes(), key.to_bytes());
+
+        // An invalid key is replaced.
+        fs::write(&path, b"short").unwrap();
+        let new_key = get_or_create_signing_key(&path).unwrap();
+        assert_ne!(new_key.to_bytes(), key.to_bytes());
+        assert_eq!(fs::read(&path).unwrap(), new_key.secret.as_bytes());
+    }
+}
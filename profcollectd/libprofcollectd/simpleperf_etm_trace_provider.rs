@@ -16,12 +16,15 @@
 
 //! Trace provider backed by ARM Coresight ETM, using simpleperf tool.
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use simpleperf_profcollect::BinaryFilter;
 use std::fs::{read_dir, remove_file};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::time::Duration;
 use trace_provider::TraceProvider;
 
+use crate::branch_list_merger::merge_branch_list;
 use crate::trace_provider;
 
 static ETM_TRACEFILE_EXTENSION: &str = "etmtrace";
@@ -34,17 +37,36 @@ impl TraceProvider for SimpleperfEtmTraceProvider {
         "simpleperf_etm"
     }
 
-    fn trace(&self, trace_dir: &Path, tag: &str, sampling_period: &Duration) {
-        let trace_file = trace_provider::get_path(trace_dir, tag, ETM_TRACEFILE_EXTENSION);
+    fn trace(
+        &self,
+        trace_dir: &Path,
+        tag: &str,
+        sampling_period: &Duration,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let trace_file =
+            trace_provider::get_path(trace_dir, tag, ETM_TRACEFILE_EXTENSION, timestamp);
 
         simpleperf_profcollect::record(
             &*trace_file,
             sampling_period,
             simpleperf_profcollect::RecordScope::BOTH,
-        );
+            &simpleperf_profcollect::RecordOptions::default(),
+        )
+        .map_err(|e| {
+            // Do not leave a partial trace behind for process() to choke on.
+            remove_file(&trace_file).ok();
+            e
+        })
+        .with_context(|| format!("Failed to record ETM trace {}", trace_file.display()))
     }
 
-    fn process(&self, trace_dir: &Path, profile_dir: &Path) -> Result<()> {
+    fn process(
+        &self,
+        trace_dir: &Path,
+        profile_dir: &Path,
+        binary_filter: &BinaryFilter,
+    ) -> Result<()> {
         read_dir(trace_dir)?
             .filter_map(|e| e.ok())
             .map(|e| e.path())
@@ -56,14 +78,23 @@ impl TraceProvider for SimpleperfEtmTraceProvider {
                         .is_some()
             })
             .try_for_each(|trace_file| -> Result<()> {
-                let mut profile_file = PathBuf::from(profile_dir);
-                profile_file.push(
-                    trace_file
-                        .file_name()
-                        .ok_or_else(|| anyhow!("Malformed trace path: {}", trace_file.display()))?,
-                );
-                profile_file.set_extension(ETM_PROFILE_EXTENSION);
-                simpleperf_profcollect::process(&trace_file, &profile_file);
+                // Branch lists are kept with the traces until merged, so that the profile
+                // directory only ever holds complete aggregated profiles.
+                let profile_file = trace_file.with_extension(ETM_PROFILE_EXTENSION);
+                match simpleperf_profcollect::process(&trace_file, &profile_file, binary_filter) {
+                    Ok(()) => merge_branch_list(&profile_file, profile_dir)?,
+                    Err(simpleperf_profcollect::Error::InvalidBinaryFilter) => {
+                        // Keep the traces until the filter is fixed, rather than profiling
+                        // binaries it was meant to leave out.
+                        bail!("Invalid binary filter {:?}", binary_filter);
+                    }
+                    Err(e) => {
+                        // The trace cannot be decoded, retrying later will not help. Drop it
+                        // along with any partially written profile.
+                        log::error!("Failed to process trace {}: {}", trace_file.display(), e);
+                        remove_file(&profile_file).ok();
+                    }
+                }
                 remove_file(&trace_file)?;
                 Ok(())
             })
@@ -72,6 +103,12 @@ impl TraceProvider for SimpleperfEtmTraceProvider {
 
 impl SimpleperfEtmTraceProvider {
     pub fn supported() -> bool {
-        simpleperf_profcollect::has_support()
+        match simpleperf_profcollect::has_support() {
+            Ok(()) => true,
+            Err(e) => {
+                log::info!("simpleperf_etm trace provider not supported: {}", e);
+                false
+            }
+        }
     }
 }
@@ -0,0 +1,116 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Trace provider backed by perf event sampling with call stacks, using simpleperf tool.
+//! Used on devices without ETM support.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use simpleperf_profcollect::{BinaryFilter, CallGraph, RecordOptions, SamplingEvent};
+use std::fs::{read_dir, remove_file};
+use std::path::Path;
+use std::time::Duration;
+use trace_provider::TraceProvider;
+
+use crate::sample_merger;
+use crate::trace_provider;
+
+static SAMPLING_TRACEFILE_EXTENSION: &str = "perfdata";
+static SAMPLING_REPORT_EXTENSION: &str = "csv";
+
+pub struct SimpleperfSamplingTraceProvider {
+    event: SamplingEvent,
+}
+
+impl TraceProvider for SimpleperfSamplingTraceProvider {
+    fn get_name(&self) -> &'static str {
+        "simpleperf_sampling"
+    }
+
+    fn trace(
+        &self,
+        trace_dir: &Path,
+        tag: &str,
+        sampling_period: &Duration,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()> {
+        let trace_file =
+            trace_provider::get_path(trace_dir, tag, SAMPLING_TRACEFILE_EXTENSION, timestamp);
+        let options = RecordOptions::default().call_graph(CallGraph::FramePointer);
+
+        simpleperf_profcollect::record_sampling(
+            &*trace_file,
+            sampling_period,
+            &self.event,
+            &options,
+        )
+        .map_err(|e| {
+            remove_file(&trace_file).ok();
+            e
+        })
+        .with_context(|| format!("Failed to record sampling trace {}", trace_file.display()))
+    }
+
+    fn process(
+        &self,
+        trace_dir: &Path,
+        profile_dir: &Path,
+        _binary_filter: &BinaryFilter,
+    ) -> Result<()> {
+        // Sample profiles are small, and binaries cannot be filtered by regex at report time, so
+        // the binary filter is not applied.
+        read_dir(trace_dir)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|e| {
+                e.is_file()
+                    && e.extension()
+                        .and_then(|f| f.to_str())
+                        .filter(|ext| ext == &SAMPLING_TRACEFILE_EXTENSION)
+                        .is_some()
+            })
+            .try_for_each(|trace_file| -> Result<()> {
+                // Reports are merged into the profile of each binary they sample.
+                let report_file = trace_file.with_extension(SAMPLING_REPORT_EXTENSION);
+                let result = simpleperf_profcollect::process_sampling(&trace_file, &report_file)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| sample_merger::merge_samples(&report_file, profile_dir));
+                if let Err(e) = result {
+                    log::error!("Failed to process trace {}: {:#}", trace_file.display(), e);
+                    remove_file(&report_file).ok();
+                }
+                remove_file(&trace_file)?;
+                Ok(())
+            })
+    }
+}
+
+impl SimpleperfSamplingTraceProvider {
+    /// Returns a provider using the most precise event the system supports, or None if no
+    /// sampling event can be recorded.
+    pub fn new() -> Option<Self> {
+        vec![SamplingEvent::Cycles, SamplingEvent::CpuClock]
+            .into_iter()
+            .find(|event| match simpleperf_profcollect::has_sampling_support(event) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::info!("Sampling event {} not supported: {}", event, e);
+                    false
+                }
+            })
+            .map(|event| SimpleperfSamplingTraceProvider { event })
+    }
+}
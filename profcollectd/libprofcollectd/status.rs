@@ -0,0 +1,91 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! ProfCollect service status, reported through get_status.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::read_dir;
+use std::path::Path;
+
+use crate::config::{Config, CONFIG_FILE, PROFILE_OUTPUT_DIR, REPORT_OUTPUT_DIR, TRACE_OUTPUT_DIR};
+use crate::scheduler::Scheduler;
+
+/// Number of files and bytes used in a directory.
+#[derive(Serialize)]
+pub struct DirUsage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl DirUsage {
+    /// Non-recursive, ignoring `exclude`.
+    fn new(path: &Path, exclude: Option<&Path>) -> Result<Self> {
+        let mut usage = DirUsage { files: 0, bytes: 0 };
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && Some(entry.path().as_path()) != exclude {
+                usage.files += 1;
+                usage.bytes += metadata.len();
+            }
+        }
+        Ok(usage)
+    }
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    /// Whether periodic collection is scheduled.
+    pub scheduled: bool,
+    pub trace_provider: &'static str,
+    pub config: Config,
+    /// RFC 3339 times of the last successful trace and the next periodic trace.
+    pub last_trace: Option<String>,
+    pub next_trace: Option<String>,
+    pub last_error: Option<String>,
+    /// Traces skipped by the collection policy, by reason.
+    pub skipped: BTreeMap<String, u64>,
+    /// Pending traces, compared against config.max_trace_limit.
+    pub traces: DirUsage,
+    pub profiles: DirUsage,
+    pub reports: DirUsage,
+}
+
+impl Status {
+    pub fn new(scheduler: &Scheduler, config: &Config) -> Result<Self> {
+        let history = scheduler.get_history();
+        Ok(Status {
+            scheduled: scheduler.is_scheduled(),
+            trace_provider: scheduler.get_trace_provider_name(),
+            config: config.clone(),
+            last_trace: history.last_trace.map(|t| t.to_rfc3339()),
+            next_trace: history.next_trace.map(|t| t.to_rfc3339()),
+            last_error: history.last_error,
+            skipped: history.skipped,
+            traces: DirUsage::new(&TRACE_OUTPUT_DIR, None)?,
+            profiles: DirUsage::new(&PROFILE_OUTPUT_DIR, Some(&CONFIG_FILE))?,
+            reports: DirUsage::new(&REPORT_OUTPUT_DIR, None)?,
+        })
+    }
+}
+
+impl ToString for Status {
+    fn to_string(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialise status.")
+    }
+}
@@ -0,0 +1,287 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Timing of periodic collections: jitter, daily collection windows and daily cap.
+//!
+//! Windows and days are in the device time zone, at the offset from UTC in effect when the next
+//! collection is scheduled.
+
+use anyhow::{anyhow, ensure, Context, Result};
+use chrono::{DateTime, Duration as ChronoDuration, FixedOffset, NaiveDate, NaiveTime, TimeZone};
+use rand::Rng;
+use std::time::Duration;
+
+use crate::config::{CollectionJitter, Config};
+
+/// Jittered intervals are at least this fraction of the configured interval, so that collections
+/// never run back to back.
+const MIN_INTERVAL_FACTOR: f64 = 0.1;
+
+/// A daily time range, which may span midnight.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CollectionWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl CollectionWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+
+    fn len(&self) -> ChronoDuration {
+        let len = self.end - self.start;
+        if len < ChronoDuration::zero() {
+            len + ChronoDuration::days(1)
+        } else {
+            len
+        }
+    }
+
+    /// The first start of this window after `t`.
+    fn next_start(&self, t: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        let start = at(get_date(t), self.start, t.offset());
+        if start > t {
+            start
+        } else {
+            start + ChronoDuration::days(1)
+        }
+    }
+}
+
+/// Parse the "collection_windows" flag.
+pub fn parse_collection_windows(windows: &str) -> Result<Vec<CollectionWindow>> {
+    windows
+        .split(';')
+        .map(str::trim)
+        .filter(|window| !window.is_empty())
+        .map(|window| {
+            let (start, end) =
+                window.split_once('-').ok_or_else(|| anyhow!("Invalid window: {}", window))?;
+            let parse = |time: &str| {
+                NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                    .with_context(|| format!("Invalid window: {}", window))
+            };
+            let window = CollectionWindow { start: parse(start)?, end: parse(end)? };
+            ensure!(window.start != window.end, "Empty window: {:?}", window);
+            Ok(window)
+        })
+        .collect()
+}
+
+/// Decides when the next periodic collection happens.
+pub struct CollectionTiming {
+    interval: Duration,
+    jitter: CollectionJitter,
+    windows: Vec<CollectionWindow>,
+    max_daily_traces: u32,
+}
+
+impl CollectionTiming {
+    pub fn new(config: &Config) -> Self {
+        let windows = parse_collection_windows(&config.collection_windows).unwrap_or_else(|e| {
+            log::error!("Ignoring invalid collection_windows flag: {:#}", e);
+            Vec::new()
+        });
+        CollectionTiming {
+            interval: config.collection_interval,
+            jitter: config.collection_jitter,
+            windows,
+            max_daily_traces: config.max_daily_traces,
+        }
+    }
+
+    /// Time of the next periodic collection, given the local time `now` and the number of
+    /// periodic collections so far on the same local day.
+    pub fn next_trace_time(
+        &self,
+        now: DateTime<FixedOffset>,
+        traces_today: u32,
+        rng: &mut impl Rng,
+    ) -> DateTime<FixedOffset> {
+        let mut next = now + to_chrono(self.get_interval(rng));
+        if self.max_daily_traces > 0
+            && traces_today >= self.max_daily_traces
+            && get_date(next) == get_date(now)
+        {
+            let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+            next = at(get_date(now) + ChronoDuration::days(1), midnight, now.offset());
+        }
+        if self.windows.is_empty() || self.windows.iter().any(|w| w.contains(next.time())) {
+            return next;
+        }
+
+        // Defer to the next window, at a random offset so that devices don't all trace as soon
+        // as it opens.
+        let window = self.windows.iter().min_by_key(|w| w.next_start(next)).unwrap();
+        let spread = window.len().min(to_chrono(self.interval));
+        let offset = match spread.num_milliseconds() {
+            millis if millis > 0 => ChronoDuration::milliseconds(rng.gen_range(0..millis)),
+            _ => ChronoDuration::zero(),
+        };
+        window.next_start(next) + offset
+    }
+
+    fn get_interval(&self, rng: &mut impl Rng) -> Duration {
+        let factor = match self.jitter {
+            CollectionJitter::None => return self.interval,
+            CollectionJitter::Uniform(percent) => {
+                let spread = percent as f64 / 100.0;
+                1.0 + rng.gen_range(-spread..=spread)
+            }
+            // 1 - U is in (0, 1], which keeps the logarithm finite.
+            CollectionJitter::Exponential => -(1.0 - rng.gen::<f64>()).ln(),
+        };
+        Duration::from_secs_f64(self.interval.as_secs_f64() * factor.max(MIN_INTERVAL_FACTOR))
+    }
+}
+
+fn get_date(t: DateTime<FixedOffset>) -> NaiveDate {
+    t.naive_local().date()
+}
+
+fn at(date: NaiveDate, time: NaiveTime, offset: &FixedOffset) -> DateTime<FixedOffset> {
+    offset.from_local_datetime(&date.and_time(time)).unwrap()
+}
+
+fn to_chrono(duration: Duration) -> ChronoDuration {
+    // Longer intervals are out of chrono's range, and make no practical difference.
+    ChronoDuration::from_std(duration).unwrap_or_else(|_| ChronoDuration::days(365))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn timing(interval_secs: u64, jitter: CollectionJitter, windows: &str) -> CollectionTiming {
+        let mut config = Config::for_test();
+        config.collection_interval = Duration::from_secs(interval_secs);
+        config.collection_jitter = jitter;
+        config.collection_windows = windows.to_string();
+        CollectionTiming::new(&config)
+    }
+
+    fn time(s: &str) -> DateTime<FixedOffset> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_windows() {
+        let windows = parse_collection_windows(" 22:00-02:00 ;12:30-13:00;").unwrap();
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].len(), ChronoDuration::hours(4));
+        assert_eq!(windows[1].len(), ChronoDuration::minutes(30));
+        assert!(parse_collection_windows("").unwrap().is_empty());
+        assert!(parse_collection_windows("22:00").is_err());
+        assert!(parse_collection_windows("22:00-25:00").is_err());
+        assert!(parse_collection_windows("10:00-10:00").is_err());
+    }
+
+    #[test]
+    fn uniform_jitter_stays_in_range() {
+        let timing = timing(1000, CollectionJitter::Uniform(20), "");
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = time("2021-06-01T12:00:00Z");
+        let delays: Vec<i64> = (0..1000)
+            .map(|_| (timing.next_trace_time(now, 0, &mut rng) - now).num_seconds())
+            .collect();
+        assert!(delays.iter().all(|d| (800..=1200).contains(d)));
+        assert!(delays.iter().any(|d| *d < 900) && delays.iter().any(|d| *d > 1100));
+    }
+
+    #[test]
+    fn jittered_interval_clamped() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = time("2021-06-01T12:00:00Z");
+        for jitter in &[CollectionJitter::Uniform(100), CollectionJitter::Exponential] {
+            let timing = timing(1000, *jitter, "");
+            let shortest = (0..10000)
+                .map(|_| (timing.next_trace_time(now, 0, &mut rng) - now).num_milliseconds())
+                .min()
+                .unwrap();
+            assert_eq!(shortest, 100_000, "{}", jitter);
+        }
+    }
+
+    #[test]
+    fn exponential_jitter_keeps_mean() {
+        let timing = timing(1000, CollectionJitter::Exponential, "");
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = time("2021-06-01T12:00:00Z");
+        let total: i64 = (0..10000)
+            .map(|_| (timing.next_trace_time(now, 0, &mut rng) - now).num_milliseconds())
+            .sum();
+        let mean = total / 10000;
+        assert!((900_000..1_100_000).contains(&mean), "mean {}ms", mean);
+    }
+
+    #[test]
+    fn traces_deferred_to_next_window() {
+        let timing = timing(600, CollectionJitter::None, "22:00-02:00;12:30-13:00");
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // Within a window, across midnight.
+        let now = time("2021-06-01T23:55:00Z");
+        assert_eq!(timing.next_trace_time(now, 0, &mut rng), time("2021-06-02T00:05:00Z"));
+
+        // Past the end of a window, deferred to the next one within its first interval.
+        for now in &["2021-06-01T01:55:00Z", "2021-06-01T12:00:00Z"] {
+            let next = timing.next_trace_time(time(now), 0, &mut rng);
+            assert!(next >= time("2021-06-01T12:30:00Z"), "{}", next);
+            assert!(next < time("2021-06-01T12:40:00Z"), "{}", next);
+        }
+        let next = timing.next_trace_time(time("2021-06-01T12:55:00Z"), 0, &mut rng);
+        assert!(next >= time("2021-06-01T22:00:00Z") && next < time("2021-06-01T22:10:00Z"));
+    }
+
+    #[test]
+    fn windows_in_local_time() {
+        let timing = timing(600, CollectionJitter::None, "22:00-02:00");
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = time("2021-06-01T23:55:00+02:00");
+        assert_eq!(timing.next_trace_time(now, 0, &mut rng), time("2021-06-02T00:05:00+02:00"));
+        // The next trace would be at 02:05 local, past the local window but inside it in UTC.
+        let now = time("2021-06-01T01:55:00+02:00");
+        let next = timing.next_trace_time(now, 0, &mut rng);
+        assert!(next >= time("2021-06-01T22:00:00+02:00"), "{}", next);
+        assert!(next < time("2021-06-01T22:10:00+02:00"), "{}", next);
+    }
+
+    #[test]
+    fn daily_cap_defers_to_next_day() {
+        let mut config = Config::for_test();
+        config.max_daily_traces = 3;
+        config.collection_windows = "01:00-02:00".to_string();
+        let timing = CollectionTiming::new(&config);
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = time("2021-06-01T01:30:00Z");
+        assert_eq!(timing.next_trace_time(now, 2, &mut rng), time("2021-06-01T01:40:00Z"));
+        let next = timing.next_trace_time(now, 3, &mut rng);
+        assert!(next >= time("2021-06-02T01:00:00Z") && next < time("2021-06-02T01:10:00Z"));
+
+        // Days start at local midnight.
+        config.collection_windows = "".to_string();
+        let timing = CollectionTiming::new(&config);
+        let now = time("2021-06-01T23:30:00+02:00");
+        assert_eq!(timing.next_trace_time(now, 3, &mut rng), time("2021-06-02T00:00:00+02:00"));
+    }
+}
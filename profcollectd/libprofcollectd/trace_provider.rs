@@ -17,20 +17,34 @@
 //! ProfCollect trace provider trait and helper functions.
 
 use anyhow::{anyhow, Result};
-use chrono::Utc;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use simpleperf_profcollect::BinaryFilter;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use crate::simpleperf_etm_trace_provider::SimpleperfEtmTraceProvider;
+use crate::simpleperf_sampling_trace_provider::SimpleperfSamplingTraceProvider;
 
 #[cfg(feature = "test")]
 use crate::logging_trace_provider::LoggingTraceProvider;
 
 pub trait TraceProvider {
     fn get_name(&self) -> &'static str;
-    fn trace(&self, trace_dir: &Path, tag: &str, sampling_period: &Duration);
-    fn process(&self, trace_dir: &Path, profile_dir: &Path) -> Result<()>;
+    /// Capture a trace tagged `tag` into `trace_dir`, named after `timestamp`.
+    fn trace(
+        &self,
+        trace_dir: &Path,
+        tag: &str,
+        sampling_period: &Duration,
+        timestamp: DateTime<Utc>,
+    ) -> Result<()>;
+    fn process(
+        &self,
+        trace_dir: &Path,
+        profile_dir: &Path,
+        binary_filter: &BinaryFilter,
+    ) -> Result<()>;
 }
 
 pub fn get_trace_provider() -> Result<Arc<Mutex<dyn TraceProvider + Send>>> {
@@ -45,13 +59,41 @@ pub fn get_trace_provider() -> Result<Arc<Mutex<dyn TraceProvider + Send>>> {
         return Ok(Arc::new(Mutex::new(LoggingTraceProvider {})));
     }
 
+    if let Some(p) = SimpleperfSamplingTraceProvider::new() {
+        log::info!("simpleperf_sampling trace provider registered.");
+        return Ok(Arc::new(Mutex::new(p)));
+    }
+
     Err(anyhow!("No trace provider found for this device."))
 }
 
-pub fn get_path(dir: &Path, tag: &str, ext: &str) -> Box<Path> {
-    let filename = format!("{}_{}", Utc::now().format("%Y%m%d-%H%M%S"), tag);
+const TRACE_TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+pub fn get_path(dir: &Path, tag: &str, ext: &str, timestamp: DateTime<Utc>) -> Box<Path> {
+    let filename = format!("{}_{}", timestamp.format(TRACE_TIMESTAMP_FORMAT), tag);
     let mut trace_file = PathBuf::from(dir);
     trace_file.push(filename);
     trace_file.set_extension(ext);
     trace_file.into_boxed_path()
 }
+
+/// Recover the capture time and tag from a path created by `get_path`.
+pub fn parse_path(path: &Path) -> Option<(DateTime<Utc>, String)> {
+    let (timestamp, tag) = path.file_stem()?.to_str()?.split_once('_')?;
+    let timestamp = NaiveDateTime::parse_from_str(timestamp, TRACE_TIMESTAMP_FORMAT).ok()?;
+    Some((Utc.from_utc_datetime(&timestamp), tag.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_roundtrip() {
+        let timestamp = "2021-06-01T12:34:56Z".parse::<DateTime<Utc>>().unwrap();
+        let path = get_path(Path::new("/trace"), "app_launch", "etmtrace", timestamp);
+        assert_eq!(&*path, Path::new("/trace/20210601-123456_app_launch.etmtrace"));
+        assert_eq!(parse_path(&path), Some((timestamp, "app_launch".to_string())));
+        assert_eq!(parse_path(Path::new("/output/libc.so.data")), None);
+    }
+}
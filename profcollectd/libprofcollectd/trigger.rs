@@ -0,0 +1,268 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Triggers starting one-off traces on events: when tasks stall on CPU or IO, or when system
+//! properties are set.
+//!
+//! Pressure triggers are registered with the kernel by writing "some <stall us> <window us>" to
+//! /proc/pressure/<resource>, after which poll() reports POLLPRI on that file whenever some tasks
+//! were stalled on the resource for longer than the stall time within a window. See
+//! Documentation/accounting/psi.rst in the kernel tree.
+
+use anyhow::{bail, ensure, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::policy::parse_psi_avg10;
+use crate::trace_provider;
+
+/// Window over which stalls are measured. Kernels restrict the triggers of processes without
+/// CAP_SYS_RESOURCE to multiples of 2s.
+pub const PSI_WINDOW: Duration = Duration::from_secs(2);
+
+/// Extension of the trigger events stored next to profiles.
+pub const TRIGGER_EXTENSION: &str = "trigger";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PressureResource {
+    Cpu,
+    Io,
+}
+
+impl PressureResource {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            PressureResource::Cpu => "cpu",
+            PressureResource::Io => "io",
+        }
+    }
+
+    /// Tag of the traces triggered by pressure on this resource.
+    pub fn get_tag(&self) -> &'static str {
+        match self {
+            PressureResource::Cpu => "psi_cpu",
+            PressureResource::Io => "psi_io",
+        }
+    }
+
+    /// PSI file of the resource, relative to the root of the file system.
+    fn get_path(&self) -> &'static str {
+        match self {
+            PressureResource::Cpu => "proc/pressure/cpu",
+            PressureResource::Io => "proc/pressure/io",
+        }
+    }
+}
+
+/// The pressure triggers enabled in `config`, with their thresholds.
+pub fn get_pressure_triggers(config: &Config) -> Vec<(PressureResource, u32)> {
+    [
+        (PressureResource::Cpu, config.cpu_pressure_trigger),
+        (PressureResource::Io, config.io_pressure_trigger),
+    ]
+    .iter()
+    .copied()
+    .filter(|(_, threshold)| *threshold > 0)
+    .collect()
+}
+
+/// Source of pressure stall notifications, replaced by a fake in tests.
+pub trait PressureMonitor: Send {
+    /// Block until the pressure crosses the threshold, or `timeout` elapses. Returns whether it
+    /// did.
+    fn wait(&mut self, timeout: Duration) -> Result<bool>;
+}
+
+/// A kernel PSI trigger.
+pub struct PsiTrigger {
+    file: File,
+}
+
+impl PsiTrigger {
+    /// Register a trigger firing whenever some tasks are stalled on `resource` for `threshold`
+    /// percent of PSI_WINDOW. Fails on kernels without PSI.
+    pub fn new(root: &Path, resource: PressureResource, threshold: u32) -> Result<Self> {
+        ensure!((1..=100).contains(&threshold), "Pressure threshold out of range: {}", threshold);
+        let path = root.join(resource.get_path());
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let window_us = PSI_WINDOW.as_micros();
+        // The kernel expects the terminating NUL.
+        let trigger = format!("some {} {}\0", window_us * threshold as u128 / 100, window_us);
+        file.write_all(trigger.as_bytes())
+            .with_context(|| format!("Failed to register trigger on {}", path.display()))?;
+        Ok(PsiTrigger { file })
+    }
+}
+
+impl PressureMonitor for PsiTrigger {
+    fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        let mut fd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLPRI, revents: 0 };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `fd` is a single valid pollfd, on a file that outlives the call.
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            0 => Ok(false),
+            ret if ret < 0 => match io::Error::last_os_error() {
+                e if e.kind() == ErrorKind::Interrupted => Ok(false),
+                e => Err(e.into()),
+            },
+            _ if fd.revents & libc::POLLERR != 0 => bail!("PSI trigger destroyed"),
+            _ => Ok(fd.revents & libc::POLLPRI != 0),
+        }
+    }
+}
+
+/// Source of system property changes, replaced by a fake in tests.
+pub trait PropertyMonitor: Send {
+    /// Block until the property is set, or `timeout` elapses. Returns its new value if it was set.
+    fn wait(&mut self, timeout: Duration) -> Option<String>;
+}
+
+/// Watches a system property through its serial, which changes every time the property is set,
+/// even to the same value.
+pub struct SystemPropertyMonitor {
+    key: String,
+    serial: u32,
+}
+
+impl SystemPropertyMonitor {
+    /// Watch `key` for changes from now on, ignoring its current value.
+    pub fn new(key: &str) -> Self {
+        SystemPropertyMonitor {
+            key: key.to_string(),
+            serial: profcollect_libbase_rust::GetPropertySerial(key),
+        }
+    }
+}
+
+impl PropertyMonitor for SystemPropertyMonitor {
+    fn wait(&mut self, timeout: Duration) -> Option<String> {
+        let timeout_ms = timeout.as_millis().min(u32::MAX as u128) as u32;
+        let serial =
+            profcollect_libbase_rust::WaitForPropertyChange(&self.key, self.serial, timeout_ms);
+        if serial == self.serial {
+            return None;
+        }
+        // Settings made while the previous trace was running are coalesced into the latest value.
+        self.serial = serial;
+        Some(profcollect_libbase_rust::GetProperty(&self.key, ""))
+    }
+}
+
+/// The system properties whose changes trigger traces, listed in `config`.
+pub fn get_trigger_properties(config: &Config) -> Vec<&str> {
+    config.trigger_properties.split(',').map(str::trim).filter(|key| !key.is_empty()).collect()
+}
+
+/// Maximum length of the tag of a property triggered trace, which ends up in file names.
+const MAX_PROPERTY_TAG_LEN: usize = 64;
+
+/// Tag of the trace triggered by a property set to `value`, with characters other than ASCII
+/// alphanumerics, '-' and '_' replaced by '_' to keep trace names parseable, truncated to
+/// MAX_PROPERTY_TAG_LEN. None if the value is empty, as when the property is cleared.
+pub fn get_property_tag(value: &str) -> Option<String> {
+    if value.is_empty() {
+        return None;
+    }
+    Some(
+        value
+            .chars()
+            .take(MAX_PROPERTY_TAG_LEN)
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect(),
+    )
+}
+
+/// A pressure notification that led to a trace. Stored as JSON in the profile directory, named
+/// after the trace with TRIGGER_EXTENSION, until listed in the manifest of the next report set with
+/// the tag and capture time of the trace.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct TriggerEvent {
+    pub resource: &'static str,
+    /// Percentage of the window some tasks were stalled for.
+    pub threshold: u32,
+    pub window_ms: u64,
+    /// RFC 3339 notification time.
+    pub triggered: String,
+    /// "some avg10" pressure on the resource when notified, None if it cannot be read.
+    pub avg10: Option<f64>,
+}
+
+impl TriggerEvent {
+    pub fn new(
+        root: &Path,
+        resource: PressureResource,
+        threshold: u32,
+        triggered: DateTime<Utc>,
+    ) -> Self {
+        let avg10 = fs::read_to_string(root.join(resource.get_path()))
+            .map_err(anyhow::Error::from)
+            .and_then(|psi| parse_psi_avg10(&psi))
+            .map_err(|e| log::warn!("Cannot read {} pressure: {:#}", resource.get_name(), e))
+            .ok();
+        TriggerEvent {
+            resource: resource.get_name(),
+            threshold,
+            window_ms: PSI_WINDOW.as_millis() as u64,
+            triggered: triggered.to_rfc3339(),
+            avg10,
+        }
+    }
+
+    /// Store the event for the trace tagged `tag` captured at `timestamp`.
+    pub fn save(&self, profile_dir: &Path, tag: &str, timestamp: DateTime<Utc>) -> Result<()> {
+        let path = trace_provider::get_path(profile_dir, tag, TRIGGER_EXTENSION, timestamp);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn property_tags() {
+        assert_eq!(get_property_tag("app_launch"), Some("app_launch".to_string()));
+        assert_eq!(get_property_tag("boot-1"), Some("boot-1".to_string()));
+        assert_eq!(get_property_tag("com.example/.Main"), Some("com_example__Main".to_string()));
+        assert_eq!(get_property_tag(""), None);
+        let long = "a".repeat(MAX_PROPERTY_TAG_LEN) + "é";
+        assert_eq!(get_property_tag(&long), Some("a".repeat(MAX_PROPERTY_TAG_LEN)));
+    }
+
+    #[test]
+    fn trigger_properties() {
+        let mut config = Config::for_test();
+        assert!(get_trigger_properties(&config).is_empty());
+        config.trigger_properties = " sys.profcollectd.trigger,,debug.trigger ".to_string();
+        assert_eq!(
+            get_trigger_properties(&config),
+            vec!["sys.profcollectd.trigger", "debug.trigger"]
+        );
+    }
+}
@@ -0,0 +1,578 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Background upload of reports to the endpoint configured through the "upload_url" flag.
+//!
+//! Reports are uploaded oldest first, one at a time, as a POST of the report file with its name in
+//! the X-Profcollect-Report header. A 2xx response acknowledges the report, which is then deleted
+//! along with its profiles. Any other outcome is retried with exponential backoff. The pending
+//! reports are the report directory itself, and the backoff and daily budget are persisted, so
+//! uploads resume where they left off after a restart. The server may see a report twice if the
+//! daemon stops between the acknowledgement and the deletion.
+
+use anyhow::{ensure, Context, Result};
+use chrono::FixedOffset;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, read_dir, read_to_string, remove_file, rename};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::{Config, REPORT_OUTPUT_DIR, UPLOAD_STATE_FILE};
+use crate::http::{post_file, HttpUrl};
+use crate::report::get_report_ts;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+/// How often to look for reports when none is known to be pending.
+const POLL_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Stored as UPLOAD_STATE_FILE.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct UploadState {
+    /// Consecutive failed attempts.
+    failures: u32,
+    /// No attempt is made before this time, if set.
+    retry_after: Option<SystemTime>,
+    /// Local day since the epoch `bytes_sent` accounts for.
+    day: u64,
+    /// Bytes sent during `day`, whether or not the uploads succeeded.
+    bytes_sent: u64,
+}
+
+impl UploadState {
+    fn load(path: &Path) -> Self {
+        match read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                log::error!("Discarding unreadable upload state {}: {}", path.display(), e);
+                UploadState::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => UploadState::default(),
+            Err(e) => {
+                log::error!("Failed to read upload state {}: {}", path.display(), e);
+                UploadState::default()
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(self)?)?;
+        rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Called with the name of each acknowledged report, once deleted.
+pub type UploadCallback = Box<dyn Fn(&str) -> Result<()> + Send>;
+
+/// Uploads the reports of one directory, one step at a time. Driven by `Uploader` on the device,
+/// but usable on its own against any endpoint and directories.
+pub struct UploadWorker {
+    url: HttpUrl,
+    daily_budget: u64,
+    report_dir: PathBuf,
+    state_file: PathBuf,
+    state: UploadState,
+    on_uploaded: UploadCallback,
+    /// Set to abandon the upload in progress.
+    stop: Arc<AtomicBool>,
+}
+
+impl UploadWorker {
+    pub fn new(
+        url: &str,
+        daily_budget: u64,
+        report_dir: &Path,
+        state_file: &Path,
+        on_uploaded: UploadCallback,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let url = HttpUrl::parse(url).context("Invalid upload URL")?;
+        Ok(UploadWorker {
+            url,
+            daily_budget,
+            report_dir: report_dir.to_path_buf(),
+            state_file: state_file.to_path_buf(),
+            state: UploadState::load(state_file),
+            on_uploaded,
+            stop,
+        })
+    }
+
+    /// Upload the oldest pending report if allowed at `now`, where the device time zone is
+    /// `local_offset` from UTC. Returns how long to wait before the next step, zero if more
+    /// reports may be uploaded right away.
+    pub fn step(&mut self, now: SystemTime, local_offset: FixedOffset) -> Result<Duration> {
+        if let Some(wait) = self
+            .state
+            .retry_after
+            .and_then(|t| t.duration_since(now).ok())
+            .filter(|wait| *wait > Duration::ZERO)
+        {
+            return Ok(wait);
+        }
+
+        // The budget applies to local days, like max_daily_traces.
+        let secs = (now.duration_since(UNIX_EPOCH)?.as_secs() as i64
+            + i64::from(local_offset.local_minus_utc())) as u64;
+        let day = secs / SECS_PER_DAY;
+        if self.state.day != day {
+            self.state.day = day;
+            self.state.bytes_sent = 0;
+        }
+
+        let (report, report_name, size) = match self.get_oldest_report()? {
+            Some(report) => report,
+            None => return Ok(POLL_INTERVAL),
+        };
+        if self.state.bytes_sent + size > self.daily_budget {
+            return Ok(Duration::from_secs((day + 1) * SECS_PER_DAY - secs));
+        }
+
+        self.state.bytes_sent += size;
+        let headers = [("X-Profcollect-Report", report_name.as_str())];
+        let result = post_file(&self.url, &report, &headers, &self.stop).and_then(|status| {
+            ensure!((200..300).contains(&status), "Upload endpoint returned {}", status);
+            Ok(())
+        });
+        let wait = match result {
+            // Not the endpoint's fault, retry as soon as uploads resume.
+            Err(e) if self.stop.load(Ordering::SeqCst) => return Err(e),
+            Ok(()) => {
+                log::info!("Uploaded report {}", report_name);
+                self.state.failures = 0;
+                self.state.retry_after = None;
+                Duration::ZERO
+            }
+            Err(e) => {
+                self.state.failures = self.state.failures.saturating_add(1);
+                let backoff = MIN_BACKOFF
+                    .checked_mul(1 << (self.state.failures - 1).min(16))
+                    .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+                log::error!(
+                    "Failed to upload report {}, retrying in {}s: {:#}",
+                    report_name,
+                    backoff.as_secs(),
+                    e
+                );
+                self.state.retry_after = Some(now + backoff);
+                backoff
+            }
+        };
+        self.state.save(&self.state_file).context("Failed to save upload state.")?;
+
+        if wait == Duration::ZERO {
+            remove_file(&report)?;
+            (self.on_uploaded)(&report_name)?;
+        }
+        Ok(wait)
+    }
+
+    /// The oldest report that fits in the daily budget, with its name and size.
+    fn get_oldest_report(&self) -> Result<Option<(PathBuf, String, u64)>> {
+        let mut oldest: Option<(SystemTime, PathBuf, String, u64)> = None;
+        for entry in read_dir(&self.report_dir)? {
+            let report = entry?.path();
+            if report.extension().and_then(|e| e.to_str()) != Some("zip") {
+                continue;
+            }
+            let name = match report.file_stem().and_then(|s| s.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let size = report.metadata()?.len();
+            if size > self.daily_budget {
+                // Left to the retention period.
+                log::warn!("Report {} exceeds the daily upload budget, skipping", name);
+                continue;
+            }
+            let ts = match get_report_ts(&report) {
+                Ok(ts) => ts,
+                // Not a report, deleted on the next start.
+                Err(_) => continue,
+            };
+            if !matches!(&oldest, Some((oldest_ts, ..)) if *oldest_ts <= ts) {
+                oldest = Some((ts, report, name, size));
+            }
+        }
+        Ok(oldest.map(|(_, report, name, size)| (report, name, size)))
+    }
+}
+
+/// Runs an `UploadWorker` over REPORT_OUTPUT_DIR on a background thread. Dropping it stops the
+/// thread, abandoning any upload in progress at the next chunk, and waits for it to exit.
+pub struct Uploader {
+    /// Signal that new reports may be pending.
+    wake_ch: SyncSender<()>,
+    /// Set to stop the thread on its next wake up, and abandon the upload in progress.
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Uploader {
+    /// Start uploading, or return None if no upload endpoint is configured.
+    pub fn start(config: &Config, on_uploaded: UploadCallback) -> Result<Option<Self>> {
+        if config.upload_url.is_empty() {
+            return Ok(None);
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut worker = UploadWorker::new(
+            &config.upload_url,
+            config.upload_daily_budget,
+            &REPORT_OUTPUT_DIR,
+            &UPLOAD_STATE_FILE,
+            on_uploaded,
+            stop.clone(),
+        )?;
+
+        let (sender, receiver) = sync_channel(1);
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stop.load(Ordering::SeqCst) {
+                let now = SystemTime::now();
+                let local_offset = SystemClock.local_offset(now.into());
+                let wait = worker.step(now, local_offset).unwrap_or_else(|e| {
+                    log::error!("Report upload failed: {:#}", e);
+                    MIN_BACKOFF
+                });
+                match receiver.recv_timeout(wait) {
+                    Ok(()) | Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Ok(Some(Uploader { wake_ch: sender, stop, thread: Some(thread) }))
+    }
+
+    /// Signal that new reports were created.
+    pub fn wake(&self) {
+        // A full channel already holds a pending wake up.
+        self.wake_ch.try_send(()).ok();
+    }
+
+    /// Ask the thread to stop without waiting for it, which dropping does.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("Report upload thread panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use tempfile::TempDir;
+
+    /// An upload received by `serve`: the report name header and the body.
+    type Request = (String, Vec<u8>);
+
+    /// Answer one request with each of `statuses` in turn, on a local port. Returns the URL and
+    /// the thread, which returns the requests once all statuses were sent.
+    fn serve(statuses: &[u16]) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/upload", listener.local_addr().unwrap());
+        let statuses = statuses.to_vec();
+        let thread = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let (mut name, mut len) = (String::new(), 0);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("X-Profcollect-Report: ") {
+                        name = value.to_string();
+                    } else if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        len = value.parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                requests.push((name, body));
+                write!(&stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+            requests
+        });
+        (url, thread)
+    }
+
+    /// The name of a report created `secs` after the epoch, as a version 7 UUID.
+    fn report_name(secs: u64) -> String {
+        let millis = secs * 1000;
+        format!("{:08x}-{:04x}-7000-8000-000000000000", millis >> 16, millis & 0xffff)
+    }
+
+    /// A report directory and upload state file.
+    struct Dirs {
+        report_dir: PathBuf,
+        state_file: PathBuf,
+        _tmp: TempDir,
+    }
+
+    fn setup(reports: &[(u64, &[u8])]) -> Dirs {
+        let tmp = TempDir::new().unwrap();
+        let report_dir = tmp.path().join("report");
+        fs::create_dir(&report_dir).unwrap();
+        for (secs, contents) in reports {
+            fs::write(report_dir.join(report_name(*secs)).with_extension("zip"), contents).unwrap();
+        }
+        Dirs { report_dir, state_file: tmp.path().join("upload_state"), _tmp: tmp }
+    }
+
+    fn new_worker(
+        dirs: &Dirs,
+        url: &str,
+        daily_budget: u64,
+    ) -> (UploadWorker, Arc<Mutex<Vec<String>>>) {
+        new_stoppable_worker(dirs, url, daily_budget, Arc::new(AtomicBool::new(false)))
+    }
+
+    fn new_stoppable_worker(
+        dirs: &Dirs,
+        url: &str,
+        daily_budget: u64,
+        stop: Arc<AtomicBool>,
+    ) -> (UploadWorker, Arc<Mutex<Vec<String>>>) {
+        let uploaded = Arc::new(Mutex::new(Vec::new()));
+        let on_uploaded = uploaded.clone();
+        let worker = UploadWorker::new(
+            url,
+            daily_budget,
+            &dirs.report_dir,
+            &dirs.state_file,
+            Box::new(move |name| {
+                on_uploaded.lock().unwrap().push(name.to_string());
+                Ok(())
+            }),
+            stop,
+        )
+        .unwrap();
+        (worker, uploaded)
+    }
+
+    fn list_reports(dirs: &Dirs) -> Vec<String> {
+        let mut reports: Vec<_> = read_dir(&dirs.report_dir)
+            .unwrap()
+            .map(|e| e.unwrap().path().file_stem().unwrap().to_str().unwrap().to_string())
+            .collect();
+        reports.sort();
+        reports
+    }
+
+    fn utc() -> FixedOffset {
+        FixedOffset::east_opt(0).unwrap()
+    }
+
+    /// One hour into the given day since the epoch.
+    fn at(day: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(day * SECS_PER_DAY + 3600)
+    }
+
+    #[test]
+    fn delete_acknowledged_reports() {
+        let dirs = setup(&[(2000, b"newer"), (1000, b"older")]);
+        let (url, server) = serve(&[200, 204]);
+        let (mut worker, uploaded) = new_worker(&dirs, &url, 1000);
+
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+        assert_eq!(list_reports(&dirs), vec![report_name(2000)]);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+        assert!(list_reports(&dirs).is_empty());
+        assert_eq!(worker.step(at(1), utc()).unwrap(), POLL_INTERVAL);
+
+        assert_eq!(*uploaded.lock().unwrap(), vec![report_name(1000), report_name(2000)]);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![(report_name(1000), b"older".to_vec()), (report_name(2000), b"newer".to_vec())]
+        );
+    }
+
+    #[test]
+    fn retry_with_backoff() {
+        let dirs = setup(&[(1000, b"report")]);
+        let (url, server) = serve(&[500, 503, 200]);
+        let (mut worker, uploaded) = new_worker(&dirs, &url, 1000);
+        let now = at(1);
+
+        assert_eq!(worker.step(now, utc()).unwrap(), MIN_BACKOFF);
+        // Not retried before the backoff elapses.
+        assert_eq!(worker.step(now + MIN_BACKOFF / 2, utc()).unwrap(), MIN_BACKOFF / 2);
+        assert_eq!(worker.step(now + MIN_BACKOFF, utc()).unwrap(), MIN_BACKOFF * 2);
+        assert_eq!(list_reports(&dirs), vec![report_name(1000)]);
+        assert!(uploaded.lock().unwrap().is_empty());
+
+        assert_eq!(worker.step(now + MIN_BACKOFF * 3, utc()).unwrap(), Duration::ZERO);
+        assert!(list_reports(&dirs).is_empty());
+        assert_eq!(*uploaded.lock().unwrap(), vec![report_name(1000)]);
+        assert_eq!(server.join().unwrap().len(), 3);
+
+        // The backoff starts over after a success.
+        let (url, server) = serve(&[500]);
+        fs::write(dirs.report_dir.join(report_name(2000)).with_extension("zip"), b"").unwrap();
+        let (mut worker, _) = new_worker(&dirs, &url, 1000);
+        assert_eq!(worker.step(now + MIN_BACKOFF * 3, utc()).unwrap(), MIN_BACKOFF);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn backoff_capped() {
+        let dirs = setup(&[(1000, b"report")]);
+        let (mut worker, _) = new_worker(&dirs, "http://127.0.0.1:1/upload", 1000);
+        let mut now = at(1);
+        for _ in 0..16 {
+            now += worker.step(now, utc()).unwrap();
+        }
+        assert_eq!(worker.step(now, utc()).unwrap(), MAX_BACKOFF);
+        assert_eq!(list_reports(&dirs), vec![report_name(1000)]);
+    }
+
+    #[test]
+    fn unresolvable_host_backs_off() {
+        let dirs = setup(&[(1000, b"report")]);
+        // The host is only resolved when uploading, e.g. once the network is up.
+        let (mut worker, _) = new_worker(&dirs, "http://unresolvable.invalid/upload", 1000);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), MIN_BACKOFF);
+        assert_eq!(worker.step(at(1) + MIN_BACKOFF, utc()).unwrap(), MIN_BACKOFF * 2);
+        assert_eq!(list_reports(&dirs), vec![report_name(1000)]);
+    }
+
+    #[test]
+    fn stopped_upload_not_retried_later() {
+        let dirs = setup(&[(1000, b"report")]);
+        let (url, server) = serve(&[200]);
+        let stop = Arc::new(AtomicBool::new(true));
+        let (mut worker, uploaded) = new_stoppable_worker(&dirs, &url, 1000, stop.clone());
+        assert!(worker.step(at(1), utc()).is_err());
+        assert_eq!(list_reports(&dirs), vec![report_name(1000)]);
+        assert!(uploaded.lock().unwrap().is_empty());
+
+        // Neither the backoff nor the budget were updated.
+        stop.store(false, Ordering::SeqCst);
+        let (mut worker, _) = new_worker(&dirs, &url, 6);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn daily_budget() {
+        let dirs = setup(&[(1000, b"first"), (2000, b"second"), (3000, b"way too large")]);
+        let (url, server) = serve(&[200, 200]);
+        let (mut worker, _) = new_worker(&dirs, &url, 10);
+
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+        // "second" would exceed the budget, wait for the next day.
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::from_secs(SECS_PER_DAY - 3600));
+        assert_eq!(worker.step(at(2), utc()).unwrap(), Duration::ZERO);
+        // Reports larger than the budget are never uploaded.
+        assert_eq!(worker.step(at(3), utc()).unwrap(), POLL_INTERVAL);
+        assert_eq!(list_reports(&dirs), vec![report_name(3000)]);
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec![&report_name(1000), &report_name(2000)]
+        );
+    }
+
+    #[test]
+    fn daily_budget_in_local_time() {
+        let dirs = setup(&[(1000, b"first"), (2000, b"second")]);
+        let (url, server) = serve(&[200, 200]);
+        let (mut worker, _) = new_worker(&dirs, &url, 10);
+        let local_offset = FixedOffset::west_opt(2 * 60 * 60).unwrap();
+
+        // 01:00 UTC is still the previous day at UTC-2.
+        assert_eq!(worker.step(at(1), local_offset).unwrap(), Duration::ZERO);
+        assert_eq!(worker.step(at(1), local_offset).unwrap(), Duration::from_secs(3600));
+        assert_eq!(
+            worker.step(at(1) + Duration::from_secs(3600), local_offset).unwrap(),
+            Duration::ZERO
+        );
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn failed_uploads_count_against_budget() {
+        let dirs = setup(&[(1000, b"report")]);
+        let (url, server) = serve(&[500]);
+        let (mut worker, _) = new_worker(&dirs, &url, 10);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), MIN_BACKOFF);
+        assert_eq!(
+            worker.step(at(1) + MIN_BACKOFF, utc()).unwrap(),
+            Duration::from_secs(SECS_PER_DAY - 3600) - MIN_BACKOFF
+        );
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn resume_from_state_file() {
+        let dirs = setup(&[(1000, b"first"), (2000, b"second")]);
+        let (url, server) = serve(&[200, 500]);
+        {
+            let (mut worker, _) = new_worker(&dirs, &url, 1000);
+            assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+            assert_eq!(worker.step(at(1), utc()).unwrap(), MIN_BACKOFF);
+        }
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        // A restarted worker keeps backing off, and keeps the budget used today.
+        let (url, server) = serve(&[200]);
+        let (mut worker, _) = new_worker(&dirs, &url, 11);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), MIN_BACKOFF);
+        assert_eq!(
+            worker.step(at(1) + MIN_BACKOFF, utc()).unwrap(),
+            Duration::from_secs(SECS_PER_DAY - 3600) - MIN_BACKOFF
+        );
+        assert_eq!(worker.step(at(2), utc()).unwrap(), Duration::ZERO);
+        assert!(list_reports(&dirs).is_empty());
+        assert_eq!(server.join().unwrap(), vec![(report_name(2000), b"second".to_vec())]);
+    }
+
+    #[test]
+    fn discard_corrupt_state_file() {
+        let dirs = setup(&[(1000, b"report")]);
+        fs::write(&dirs.state_file, "garbage").unwrap();
+        let (url, server) = serve(&[200]);
+        let (mut worker, _) = new_worker(&dirs, &url, 1000);
+        assert_eq!(worker.step(at(1), utc()).unwrap(), Duration::ZERO);
+        assert_eq!(server.join().unwrap().len(), 1);
+    }
+}
@@ -18,6 +18,7 @@
 
 use anyhow::{bail, Context, Result};
 use std::env;
+use std::path::Path;
 
 const HELP_MSG: &str = r#"
 usage: profcollectctl [command]
@@ -30,8 +31,13 @@ command:
     once        Request an one-off trace.
     process     Convert traces to perf profiles.
     reconfig    Refresh configuration.
-    report      Create a report containing all profiles.
+    report      Create a report containing profiles not reported yet.
+    report-full Create a report containing all profiles.
+    reports     List the stored reports.
+    inspect     Print the contents and manifest of a stored report, e.g. `inspect <report>`.
     reset       Clear all local data.
+    status      Print the service status.
+    verify      Verify the signature and contents of a report, e.g. `verify <file>`.
     help        Print this message.
 "#;
 
@@ -39,8 +45,12 @@ fn main() -> Result<()> {
     libprofcollectd::init_logging();
 
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        bail!("This program only takes one argument{}", &HELP_MSG);
+    let expected_args = match args.get(1).map(String::as_str) {
+        Some("verify") | Some("inspect") => 3,
+        _ => 2,
+    };
+    if args.len() != expected_args {
+        bail!("Wrong number of arguments{}", &HELP_MSG);
     }
 
     let action = &args[1];
@@ -61,15 +71,43 @@ fn main() -> Result<()> {
             println!("Processing traces");
             libprofcollectd::process().context("Failed to process traces.")?;
         }
-        "report" => {
+        "reconfig" => {
+            println!("Refreshing configuration");
+            libprofcollectd::reconfig().context("Failed to refresh configuration.")?;
+        }
+        "report" | "report-full" => {
             println!("Creating profile report");
-            let path = libprofcollectd::report().context("Failed to create profile report.")?;
-            println!("Report created at: {}", &path);
+            let reports = libprofcollectd::report(action == "report-full")
+                .context("Failed to create profile report.")?;
+            if reports.is_empty() {
+                println!("No new profiles to report");
+            }
+            for report in &reports {
+                println!("Report created at: {}", report);
+            }
+        }
+        "reports" => {
+            let reports = libprofcollectd::list_reports().context("Failed to list reports.")?;
+            println!("{}", &reports);
+        }
+        "inspect" => {
+            let contents =
+                libprofcollectd::inspect_report(&args[2]).context("Failed to inspect report.")?;
+            println!("{}", &contents);
         }
         "reset" => {
             libprofcollectd::reset().context("Failed to reset.")?;
             println!("Reset done.");
         }
+        "status" => {
+            let status = libprofcollectd::get_status().context("Failed to get status.")?;
+            println!("{}", &status);
+        }
+        "verify" => {
+            let public_key = libprofcollectd::verify_report(Path::new(&args[2]))
+                .context("Failed to verify report.")?;
+            println!("Report verified, signed by {}", &public_key);
+        }
         "help" => println!("{}", &HELP_MSG),
         arg => bail!("Unknown argument: {}\n{}", &arg, &HELP_MSG),
     }
@@ -164,6 +164,9 @@ bool ETMRecorder::ReadEtmInfo() {
 }
 
 bool ETMRecorder::FindSinkConfig() {
+  if (!sink_name_.empty()) {
+    return ReadValueInEtmDir("sinks/" + sink_name_, &sink_config_);
+  }
   bool has_etr = false;
   bool has_trbe = false;
   for (const auto& name : GetEntriesInDir(ETM_DIR + "sinks")) {
@@ -20,6 +20,7 @@
 
 #include <map>
 #include <memory>
+#include <string>
 
 #include "event_type.h"
 #include "perf_event.h"
@@ -58,6 +59,9 @@ class ETMRecorder {
   void SetEtmPerfEventAttr(perf_event_attr* attr);
   AuxTraceInfoRecord CreateAuxTraceInfoRecord();
   size_t GetAddrFilterPairs();
+  // Select the sink in /sys/bus/event_source/devices/cs_etm/sinks by name. An empty name lets
+  // ETMRecorder pick the sink automatically.
+  void SetSinkName(const std::string& name) { sink_name_ = name; }
 
  private:
   bool ReadEtmInfo();
@@ -68,6 +72,7 @@ class ETMRecorder {
   bool etm_supported_ = false;
   // select ETR device, setting in perf_event_attr->config2
   uint32_t sink_config_ = 0;
+  std::string sink_name_;
   // select etm options (timestamp, context_id, ...), setting in perf_event_attr->config
   uint64_t etm_event_config_ = 0;
   // record etm options in AuxTraceInfoRecord
@@ -106,6 +106,7 @@ class InjectCommand : public Command {
                 // clang-format off
 "Usage: simpleperf inject [options]\n"
 "--binary binary_name         Generate data only for binaries matching binary_name regex.\n"
+"--exclude-binary binary_name Don't generate data for binaries matching binary_name regex.\n"
 "-i <file>                    Input file. Default is perf.data. Support below formats:\n"
 "                               1. perf.data generated by recording cs-etm event type.\n"
 "                               2. branch_list file generated by `inject --output branch-list`.\n"
@@ -163,6 +164,7 @@ class InjectCommand : public Command {
     const OptionFormatMap option_formats = {
         {"--binary", {OptionValueType::STRING, OptionType::SINGLE}},
         {"--dump-etm", {OptionValueType::STRING, OptionType::SINGLE}},
+        {"--exclude-binary", {OptionValueType::STRING, OptionType::SINGLE}},
         {"--exclude-perf", {OptionValueType::NONE, OptionType::SINGLE}},
         {"-i", {OptionValueType::STRING, OptionType::SINGLE}},
         {"-o", {OptionValueType::STRING, OptionType::SINGLE}},
@@ -178,6 +180,9 @@ class InjectCommand : public Command {
     if (auto value = options.PullValue("--binary"); value) {
       binary_name_regex_ = *value->str_value;
     }
+    if (auto value = options.PullValue("--exclude-binary"); value) {
+      exclude_binary_name_regex_ = std::regex(*value->str_value);
+    }
     if (auto value = options.PullValue("--dump-etm"); value) {
       if (!ParseEtmDumpOption(*value->str_value, &etm_dump_option_)) {
         return false;
@@ -283,7 +288,9 @@ class InjectCommand : public Command {
     if (lookup != dso_filter_cache.end()) {
       return lookup->second;
     }
-    bool match = std::regex_search(dso->Path(), binary_name_regex_);
+    bool match = std::regex_search(dso->Path(), binary_name_regex_) &&
+                 !(exclude_binary_name_regex_ &&
+                   std::regex_search(dso->Path(), *exclude_binary_name_regex_));
     dso_filter_cache.insert({dso, match});
     return match;
   }
@@ -585,6 +592,7 @@ class InjectCommand : public Command {
   }
 
   std::regex binary_name_regex_{""};  // Default to match everything.
+  std::optional<std::regex> exclude_binary_name_regex_;
   bool exclude_perf_ = false;
   std::string input_filename_ = "perf.data";
   std::string output_filename_ = "perf_inject.data";
@@ -86,6 +86,22 @@ TEST(cmd_inject, binary_option) {
   ASSERT_EQ(data.find("etm_test_loop"), std::string::npos);
 }
 
+TEST(cmd_inject, exclude_binary_option) {
+  // Test that data for etm_test_loop isn't generated when excluded by --exclude-binary.
+  std::string data;
+  ASSERT_TRUE(RunInjectCmd({"--exclude-binary", "etm_test_loop"}, &data));
+  ASSERT_EQ(data.find("etm_test_loop"), std::string::npos);
+
+  // Test that data for etm_test_loop is generated when not excluded by --exclude-binary.
+  ASSERT_TRUE(RunInjectCmd({"--exclude-binary", "no_etm_test_.*"}, &data));
+  ASSERT_NE(data.find("etm_test_loop"), std::string::npos);
+
+  // Test that --exclude-binary takes priority over --binary.
+  ASSERT_TRUE(
+      RunInjectCmd({"--binary", "etm_t.*_loop", "--exclude-binary", "etm_test_loop"}, &data));
+  ASSERT_EQ(data.find("etm_test_loop"), std::string::npos);
+}
+
 TEST(cmd_inject, exclude_perf_option) {
   ASSERT_FALSE(RunInjectCmd({"--exclude-perf"}, nullptr));
   std::string perf_with_recording_process =
@@ -14,9 +14,31 @@
  * limitations under the License.
  */
 
+#include <stddef.h>
+
+// Status codes returned by the functions below. Keep in sync with rust/lib.rs.
+#define PROFCOLLECT_OK 0
+#define PROFCOLLECT_ERROR_PERMISSION 1
+#define PROFCOLLECT_ERROR_UNSUPPORTED_EVENT 2
+#define PROFCOLLECT_ERROR_RECORD 3
+#define PROFCOLLECT_ERROR_INJECT 4
+#define PROFCOLLECT_ERROR_REPORT 5
+#define PROFCOLLECT_ERROR_INVALID_BINARY_FILTER 6
+
 extern "C" {
 
-bool HasSupport();
-bool Record(const char* event_name, const char* output, float duration);
-bool Inject(const char* traceInput, const char* profileOutput);
+int HasSupport();
+// Check whether `event_name`, in the format accepted by `simpleperf record -e`, can be recorded.
+int IsEventSupported(const char* event_name);
+// `options` holds extra arguments passed to the record command, including the recording target
+// (e.g. "-a" or "-p <pids>"). `etm_sink` selects the ETM sink by name, or is empty to let
+// simpleperf choose one.
+int Record(const char* event_name, const char* output, float duration, const char* etm_sink,
+           const char* const* options, size_t options_size);
+// `binaryFilter` and `binaryExcludeFilter` are regexes of binaries to include and exclude, or are
+// empty to disable the corresponding filter. Nothing is injected if either regex is invalid.
+int Inject(const char* traceInput, const char* profileOutput, const char* binaryFilter,
+           const char* binaryExcludeFilter);
+// Summarize a sampling perf.data into per-binary sample counts, in csv format.
+int ReportSample(const char* perfDataInput, const char* profileOutput);
 }
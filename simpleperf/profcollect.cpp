@@ -16,41 +16,103 @@
 
 #include <include/simpleperf_profcollect.hpp>
 
+#include <regex>
+
 #include "ETMRecorder.h"
 #include "command.h"
+#include "environment.h"
 #include "event_attr.h"
 #include "event_fd.h"
 #include "event_type.h"
 
 using namespace simpleperf;
 
-bool HasSupport() {
+int HasSupport() {
   if (!ETMRecorder::GetInstance().CheckEtmSupport()) {
-    return false;
+    return PROFCOLLECT_ERROR_UNSUPPORTED_EVENT;
   }
   const EventType* type = FindEventTypeByName("cs-etm", false);
   if (type == nullptr) {
-    return false;
+    return PROFCOLLECT_ERROR_UNSUPPORTED_EVENT;
+  }
+  if (!CheckPerfEventLimit()) {
+    return PROFCOLLECT_ERROR_PERMISSION;
   }
-  return IsEventAttrSupported(CreateDefaultPerfEventAttr(*type), type->name);
+  if (!IsEventAttrSupported(CreateDefaultPerfEventAttr(*type), type->name)) {
+    return PROFCOLLECT_ERROR_UNSUPPORTED_EVENT;
+  }
+  return PROFCOLLECT_OK;
 }
 
-bool Record(const char* event_name, const char* output, float duration) {
+int IsEventSupported(const char* event_name) {
+  std::unique_ptr<EventTypeAndModifier> event = ParseEventType(event_name);
+  if (event == nullptr) {
+    return PROFCOLLECT_ERROR_UNSUPPORTED_EVENT;
+  }
+  if (!CheckPerfEventLimit()) {
+    return PROFCOLLECT_ERROR_PERMISSION;
+  }
+  // Perf events are allowed at this point, so failing to open one means the hardware or kernel
+  // doesn't support it.
+  if (!IsEventAttrSupported(CreateDefaultPerfEventAttr(event->event_type), event->name)) {
+    return PROFCOLLECT_ERROR_UNSUPPORTED_EVENT;
+  }
+  return PROFCOLLECT_OK;
+}
+
+int Record(const char* event_name, const char* output, float duration, const char* etm_sink,
+           const char* const* options, size_t options_size) {
+  if (int status = IsEventSupported(event_name); status != PROFCOLLECT_OK) {
+    return status;
+  }
+
+  ETMRecorder::GetInstance().SetSinkName(etm_sink);
+
   auto recordCmd = CreateCommandInstance("record");
-  std::vector<std::string> args;
-  args.push_back("-a");
+  std::vector<std::string> args(options, options + options_size);
   args.insert(args.end(), {"-e", event_name});
   args.insert(args.end(), {"--duration", std::to_string(duration)});
   args.insert(args.end(), {"-o", output});
-  return recordCmd->Run(args);
+  return recordCmd->Run(args) ? PROFCOLLECT_OK : PROFCOLLECT_ERROR_RECORD;
+}
+
+// The inject command aborts on an invalid regex, so check the filters in this file, which is
+// built with exceptions enabled.
+static bool IsValidRegex(const char* pattern) {
+  try {
+    std::regex re(pattern);
+  } catch (const std::regex_error&) {
+    return false;
+  }
+  return true;
 }
 
-bool Inject(const char* traceInput, const char* profileOutput) {
+int Inject(const char* traceInput, const char* profileOutput, const char* binaryFilter,
+           const char* binaryExcludeFilter) {
+  if (!IsValidRegex(binaryFilter) || !IsValidRegex(binaryExcludeFilter)) {
+    return PROFCOLLECT_ERROR_INVALID_BINARY_FILTER;
+  }
   auto injectCmd = CreateCommandInstance("inject");
   std::vector<std::string> args;
   args.insert(args.end(), {"-i", traceInput});
   args.insert(args.end(), {"-o", profileOutput});
+  if (binaryFilter[0] != '\0') {
+    args.insert(args.end(), {"--binary", binaryFilter});
+  }
+  if (binaryExcludeFilter[0] != '\0') {
+    args.insert(args.end(), {"--exclude-binary", binaryExcludeFilter});
+  }
   args.insert(args.end(), {"--output", "branch-list"});
   args.emplace_back("--exclude-perf");
-  return injectCmd->Run(args);
+  return injectCmd->Run(args) ? PROFCOLLECT_OK : PROFCOLLECT_ERROR_INJECT;
+}
+
+int ReportSample(const char* perfDataInput, const char* profileOutput) {
+  auto reportCmd = CreateCommandInstance("report");
+  std::vector<std::string> args;
+  args.insert(args.end(), {"-i", perfDataInput});
+  args.insert(args.end(), {"-o", profileOutput});
+  args.insert(args.end(), {"--sort", "dso,vaddr_in_file"});
+  args.insert(args.end(), {"--csv", "--children", "--raw-period", "-n"});
+  return reportCmd->Run(args) ? PROFCOLLECT_OK : PROFCOLLECT_ERROR_REPORT;
 }
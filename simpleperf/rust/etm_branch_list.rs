@@ -0,0 +1,546 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Reader and writer for branch list files generated by `simpleperf inject --output branch-list`.
+//! The format is the ETMBranchList message in etm_branch_list.proto, encoded by hand so that the
+//! crate has no dependency on generated protobuf code and can be used on host tools.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Magic string identifying branch list files.
+pub const ETM_BRANCH_LIST_MAGIC: &str = "simpleperf:EtmBranchList";
+
+/// Errors returned when reading a branch list.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read or write the file.
+    Io(io::Error),
+    /// The data is not a valid protobuf message.
+    Malformed(&'static str),
+    /// The magic field doesn't match `ETM_BRANCH_LIST_MAGIC`.
+    BadMagic(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Malformed(msg) => write!(f, "Malformed branch list: {}", msg),
+            Error::BadMagic(magic) => write!(f, "Unexpected branch list magic: {:?}", magic),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Result type of branch list operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A sequence of branch decisions following an instruction address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Branch {
+    /// Each bit represents a branch: 0 for not taken, 1 for taken. Bit 0 comes first.
+    pub branch: Vec<u8>,
+    /// Number of valid bits in `branch`.
+    pub branch_size: u32,
+    /// How many times this branch sequence was seen.
+    pub count: u64,
+}
+
+/// Branches recorded after an instruction address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Address {
+    /// Virtual address in the binary of the instruction before the first branch.
+    pub addr: u64,
+    pub branches: Vec<Branch>,
+}
+
+/// Kind of binary a branch list entry belongs to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BinaryType {
+    #[default]
+    ElfFile,
+    Kernel,
+    KernelModule,
+    /// A value not known to this reader, kept so that it survives a round trip.
+    Unknown(i32),
+}
+
+impl BinaryType {
+    fn from_i32(value: i32) -> Self {
+        match value {
+            0 => BinaryType::ElfFile,
+            1 => BinaryType::Kernel,
+            2 => BinaryType::KernelModule,
+            v => BinaryType::Unknown(v),
+        }
+    }
+
+    fn to_i32(self) -> i32 {
+        match self {
+            BinaryType::ElfFile => 0,
+            BinaryType::Kernel => 1,
+            BinaryType::KernelModule => 2,
+            BinaryType::Unknown(v) => v,
+        }
+    }
+}
+
+/// Branch data for one binary.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Binary {
+    pub path: String,
+    pub build_id: String,
+    pub addrs: Vec<Address>,
+    pub binary_type: BinaryType,
+    /// Used to convert kernel ip addresses to vaddrs in vmlinux. None if the message has no
+    /// kernel info, Some(0) if addresses have already been converted.
+    pub kernel_start_addr: Option<u64>,
+}
+
+/// Contents of a branch list file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EtmBranchList {
+    pub binaries: Vec<Binary>,
+}
+
+impl EtmBranchList {
+    /// Decode a branch list, checking its magic.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut magic = String::new();
+        let mut binaries = Vec::new();
+        let mut reader = Reader::new(data);
+        while let Some((field, wire_type)) = reader.read_key()? {
+            match (field, wire_type) {
+                (1, WIRE_LEN) => magic = reader.read_string()?,
+                (2, WIRE_LEN) => binaries.push(parse_binary(reader.read_bytes()?)?),
+                _ => reader.skip(wire_type)?,
+            }
+        }
+        if magic != ETM_BRANCH_LIST_MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+        Ok(EtmBranchList { binaries })
+    }
+
+    /// Encode the branch list, including its magic.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.write_bytes(1, ETM_BRANCH_LIST_MAGIC.as_bytes());
+        for binary in &self.binaries {
+            writer.write_message(2, &encode_binary(binary));
+        }
+        writer.buf
+    }
+
+    /// Read a branch list file.
+    pub fn read_from_file(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Write a branch list file, replacing any existing file.
+    pub fn write_to_file(&self, path: &Path) -> Result<()> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+}
+
+fn parse_binary(data: &[u8]) -> Result<Binary> {
+    let mut binary = Binary::default();
+    let mut reader = Reader::new(data);
+    while let Some((field, wire_type)) = reader.read_key()? {
+        match (field, wire_type) {
+            (1, WIRE_LEN) => binary.path = reader.read_string()?,
+            (2, WIRE_LEN) => binary.build_id = reader.read_string()?,
+            (3, WIRE_LEN) => binary.addrs.push(parse_address(reader.read_bytes()?)?),
+            (4, WIRE_VARINT) => {
+                binary.binary_type = BinaryType::from_i32(reader.read_varint()? as i32)
+            }
+            (5, WIRE_LEN) => {
+                let mut kernel_start_addr = 0;
+                let mut kernel_info = Reader::new(reader.read_bytes()?);
+                while let Some((field, wire_type)) = kernel_info.read_key()? {
+                    match (field, wire_type) {
+                        (1, WIRE_VARINT) => kernel_start_addr = kernel_info.read_varint()?,
+                        _ => kernel_info.skip(wire_type)?,
+                    }
+                }
+                binary.kernel_start_addr = Some(kernel_start_addr);
+            }
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(binary)
+}
+
+fn parse_address(data: &[u8]) -> Result<Address> {
+    let mut address = Address::default();
+    let mut reader = Reader::new(data);
+    while let Some((field, wire_type)) = reader.read_key()? {
+        match (field, wire_type) {
+            (1, WIRE_VARINT) => address.addr = reader.read_varint()?,
+            (2, WIRE_LEN) => address.branches.push(parse_branch(reader.read_bytes()?)?),
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(address)
+}
+
+fn parse_branch(data: &[u8]) -> Result<Branch> {
+    let mut branch = Branch::default();
+    let mut reader = Reader::new(data);
+    while let Some((field, wire_type)) = reader.read_key()? {
+        match (field, wire_type) {
+            (1, WIRE_LEN) => branch.branch = reader.read_bytes()?.to_vec(),
+            (2, WIRE_VARINT) => branch.branch_size = reader.read_varint()? as u32,
+            (3, WIRE_VARINT) => branch.count = reader.read_varint()?,
+            _ => reader.skip(wire_type)?,
+        }
+    }
+    Ok(branch)
+}
+
+fn encode_binary(binary: &Binary) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.write_bytes(1, binary.path.as_bytes());
+    writer.write_bytes(2, binary.build_id.as_bytes());
+    for address in &binary.addrs {
+        writer.write_message(3, &encode_address(address));
+    }
+    // Negative enum values are sign extended to 64 bits, as protobuf does.
+    writer.write_varint(4, binary.binary_type.to_i32() as i64 as u64);
+    if let Some(kernel_start_addr) = binary.kernel_start_addr {
+        let mut kernel_info = Writer::default();
+        kernel_info.write_varint(1, kernel_start_addr);
+        writer.write_message(5, &kernel_info.buf);
+    }
+    writer.buf
+}
+
+fn encode_address(address: &Address) -> Vec<u8> {
+    let mut writer = Writer::default();
+    writer.write_varint(1, address.addr);
+    for branch in &address.branches {
+        let mut branch_writer = Writer::default();
+        branch_writer.write_bytes(1, &branch.branch);
+        branch_writer.write_varint(2, branch.branch_size as u64);
+        branch_writer.write_varint(3, branch.count);
+        writer.write_message(2, &branch_writer.buf);
+    }
+    writer.buf
+}
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_I64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+const WIRE_I32: u8 = 5;
+
+/// Protobuf wire format decoder over a byte slice.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    /// Returns the next field number and wire type, or None at the end of the message.
+    fn read_key(&mut self) -> Result<Option<(u64, u8)>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let key = self.read_varint()?;
+        Ok(Some((key >> 3, (key & 0x7) as u8)))
+    }
+
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for (i, byte) in self.data.iter().enumerate().take(10) {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.data = &self.data[i + 1..];
+                return Ok(value);
+            }
+        }
+        Err(Error::Malformed("truncated or oversized varint"))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_varint()? as usize;
+        self.advance(len)
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let bytes = self.read_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::Malformed("invalid UTF-8 string"))
+    }
+
+    fn advance(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(Error::Malformed("field exceeds message length"));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn skip(&mut self, wire_type: u8) -> Result<()> {
+        match wire_type {
+            WIRE_VARINT => self.read_varint().map(|_| ()),
+            WIRE_I64 => self.advance(8).map(|_| ()),
+            WIRE_LEN => self.read_bytes().map(|_| ()),
+            WIRE_I32 => self.advance(4).map(|_| ()),
+            _ => Err(Error::Malformed("unsupported wire type")),
+        }
+    }
+}
+
+/// Protobuf wire format encoder. Fields with default values are omitted, as in proto3.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn put_varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    fn put_key(&mut self, field: u64, wire_type: u8) {
+        self.put_varint((field << 3) | wire_type as u64);
+    }
+
+    fn write_varint(&mut self, field: u64, value: u64) {
+        if value != 0 {
+            self.put_key(field, WIRE_VARINT);
+            self.put_varint(value);
+        }
+    }
+
+    fn write_bytes(&mut self, field: u64, value: &[u8]) {
+        if !value.is_empty() {
+            self.write_message(field, value);
+        }
+    }
+
+    /// Write a length-delimited field even if it is empty, as is done for present submessages.
+    fn write_message(&mut self, field: u64, value: &[u8]) {
+        self.put_key(field, WIRE_LEN);
+        self.put_varint(value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_list() -> EtmBranchList {
+        EtmBranchList {
+            binaries: vec![Binary {
+                path: "/a".to_string(),
+                build_id: "b1".to_string(),
+                addrs: vec![Address {
+                    addr: 0x1000,
+                    branches: vec![Branch { branch: vec![0x05], branch_size: 3, count: 7 }],
+                }],
+                binary_type: BinaryType::Kernel,
+                kernel_start_addr: Some(0x10),
+            }],
+        }
+    }
+
+    /// `sample_list()` encoded by hand from the field numbers in etm_branch_list.proto.
+    fn sample_bytes() -> Vec<u8> {
+        // Branch: branch = 1, branch_size = 2, count = 3.
+        let branch = [0x0a, 0x01, 0x05, 0x10, 0x03, 0x18, 0x07];
+        // Address: addr = 1, branches = 2.
+        let mut address = vec![0x08, 0x80, 0x20, 0x12, branch.len() as u8];
+        address.extend_from_slice(&branch);
+        // Binary: path = 1, build_id = 2, addrs = 3, type = 4, kernel_info = 5.
+        let mut binary = vec![0x0a, 0x02, b'/', b'a', 0x12, 0x02, b'b', b'1'];
+        binary.extend_from_slice(&[0x1a, address.len() as u8]);
+        binary.extend_from_slice(&address);
+        binary.extend_from_slice(&[0x20, 0x01]);
+        // KernelBinaryInfo: kernel_start_addr = 1.
+        binary.extend_from_slice(&[0x2a, 0x02, 0x08, 0x10]);
+        // ETMBranchList: magic = 1, binaries = 2.
+        let mut list = vec![0x0a, ETM_BRANCH_LIST_MAGIC.len() as u8];
+        list.extend_from_slice(ETM_BRANCH_LIST_MAGIC.as_bytes());
+        list.extend_from_slice(&[0x12, binary.len() as u8]);
+        list.extend_from_slice(&binary);
+        list
+    }
+
+    fn with_magic(magic: &str, fields: &[u8]) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.write_bytes(1, magic.as_bytes());
+        writer.buf.extend_from_slice(fields);
+        writer.buf
+    }
+
+    fn assert_malformed(data: &[u8]) {
+        match EtmBranchList::parse(data) {
+            Err(Error::Malformed(_)) => {}
+            other => panic!("Expected a malformed error for {:02x?}, got {:?}", data, other),
+        }
+    }
+
+    #[test]
+    fn encode_matches_proto() {
+        assert_eq!(sample_list().to_bytes(), sample_bytes());
+    }
+
+    #[test]
+    fn decode_matches_proto() {
+        assert_eq!(EtmBranchList::parse(&sample_bytes()).unwrap(), sample_list());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut list = sample_list();
+        list.binaries.push(Binary {
+            path: "/system/lib64/libc.so".to_string(),
+            addrs: vec![Address {
+                addr: u64::MAX,
+                branches: vec![
+                    Branch { branch: vec![0xff; 40], branch_size: 317, count: u64::MAX },
+                    Branch::default(),
+                ],
+            }],
+            ..Default::default()
+        });
+        list.binaries.push(Binary {
+            binary_type: BinaryType::KernelModule,
+            kernel_start_addr: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(EtmBranchList::parse(&list.to_bytes()).unwrap(), list);
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("etm_branch_list_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("branch_list.data");
+        sample_list().write_to_file(&path).unwrap();
+        assert_eq!(EtmBranchList::read_from_file(&path).unwrap(), sample_list());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(EtmBranchList::read_from_file(&path), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn bad_magic() {
+        match EtmBranchList::parse(&with_magic("simpleperf:Other", &[])) {
+            Err(Error::BadMagic(magic)) => assert_eq!(magic, "simpleperf:Other"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match EtmBranchList::parse(&[]) {
+            Err(Error::BadMagic(magic)) => assert!(magic.is_empty()),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_varint() {
+        // A key with the continuation bit set and nothing after it.
+        assert_malformed(&[0x80]);
+        // A binary whose length is cut off.
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x12, 0x80]));
+        // An address value cut off inside a binary.
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x12, 0x04, 0x1a, 0x02, 0x08, 0x80]));
+        // More than 10 bytes.
+        assert_malformed(&[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    }
+
+    #[test]
+    fn length_past_end() {
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x12, 0x05, 0x0a, 0x01]));
+        // The magic itself.
+        assert_malformed(&[0x0a, 0x20, b's']);
+        // Fixed size fields.
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x19, 0x00, 0x00]));
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x1d, 0x00]));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        // Group wire types are not supported.
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x1b]));
+        // Path that is not UTF-8.
+        assert_malformed(&with_magic(ETM_BRANCH_LIST_MAGIC, &[0x12, 0x03, 0x0a, 0x01, 0xff]));
+    }
+
+    #[test]
+    fn unknown_fields_skipped() {
+        // Field 15 of each wire type: varint, 64-bit, length-delimited and 32-bit.
+        let unknown = [
+            0x78, 0x96, 0x01, //
+            0x79, 1, 2, 3, 4, 5, 6, 7, 8, //
+            0x7a, 0x02, 0xaa, 0xbb, //
+            0x7d, 1, 2, 3, 4,
+        ];
+        let mut branch = vec![0x0a, 0x01, 0x05, 0x10, 0x03, 0x18, 0x07];
+        branch.extend_from_slice(&unknown);
+        let mut address = vec![0x08, 0x80, 0x20, 0x12, branch.len() as u8];
+        address.extend_from_slice(&branch);
+        address.extend_from_slice(&unknown);
+        let mut binary = unknown.to_vec();
+        binary.extend_from_slice(&[0x0a, 0x02, b'/', b'a', 0x12, 0x02, b'b', b'1']);
+        binary.extend_from_slice(&[0x1a, address.len() as u8]);
+        binary.extend_from_slice(&address);
+        binary.extend_from_slice(&[0x20, 0x01]);
+        // Unknown field in KernelBinaryInfo too.
+        binary.extend_from_slice(&[0x2a, 0x04, 0x78, 0x01, 0x08, 0x10]);
+        let mut fields = vec![0x12, binary.len() as u8];
+        fields.extend_from_slice(&binary);
+        fields.extend_from_slice(&unknown);
+
+        assert_eq!(
+            EtmBranchList::parse(&with_magic(ETM_BRANCH_LIST_MAGIC, &fields)).unwrap(),
+            sample_list()
+        );
+    }
+
+    #[test]
+    fn unknown_binary_type_kept() {
+        for value in &[3, 100, -1] {
+            let mut list = sample_list();
+            list.binaries[0].binary_type = BinaryType::Unknown(*value);
+            let bytes = list.to_bytes();
+            let parsed = EtmBranchList::parse(&bytes).unwrap();
+            assert_eq!(parsed.binaries[0].binary_type, BinaryType::Unknown(*value));
+            assert_eq!(parsed.to_bytes(), bytes);
+        }
+        // Negative values take 10 bytes, as protobuf encodes them.
+        let mut list = sample_list();
+        list.binaries[0].binary_type = BinaryType::Unknown(-1);
+        assert_eq!(list.to_bytes().len(), sample_bytes().len() + 9);
+    }
+}
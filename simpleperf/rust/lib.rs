@@ -14,20 +14,96 @@
 // limitations under the License.
 //
 
-//! This module implements safe wrappers for simpleperf etm operations required
+//! This module implements safe wrappers for simpleperf etm and sampling operations required
 //! by profcollect.
 
+mod record_options;
+mod sampling;
+
+pub use record_options::{CallGraph, RecordOptions, RecordTarget, SampleRate};
+pub use sampling::{has_sampling_support, process_sampling, record_sampling, SamplingEvent};
+
 use std::ffi::CString;
-use std::path::Path;
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-fn path_to_cstr(path: &Path) -> CString {
-    CString::new(path.to_str().unwrap()).unwrap()
+// Status codes returned by the C entry points, see include/simpleperf_profcollect.hpp.
+const PROFCOLLECT_OK: c_int = 0;
+const PROFCOLLECT_ERROR_PERMISSION: c_int = 1;
+const PROFCOLLECT_ERROR_UNSUPPORTED_EVENT: c_int = 2;
+const PROFCOLLECT_ERROR_RECORD: c_int = 3;
+const PROFCOLLECT_ERROR_INJECT: c_int = 4;
+const PROFCOLLECT_ERROR_REPORT: c_int = 5;
+const PROFCOLLECT_ERROR_INVALID_BINARY_FILTER: c_int = 6;
+
+/// Errors returned by simpleperf profcollect operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The path is not valid UTF-8 or contains an interior NUL byte.
+    InvalidPath(PathBuf),
+    /// The record options are invalid.
+    InvalidOptions(String),
+    /// perf_event_open is not permitted.
+    PermissionDenied,
+    /// The requested event is not supported by the hardware or kernel.
+    UnsupportedEvent,
+    /// The record command failed to capture a trace.
+    RecordFailed,
+    /// The inject command failed to decode a trace.
+    InjectFailed,
+    /// The report command failed to summarize a sampling profile.
+    ReportFailed,
+    /// A binary filter is not a valid regex.
+    InvalidBinaryFilter,
+    /// An unknown status code was returned by simpleperf.
+    Unknown(i32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidPath(path) => write!(f, "Invalid path: {}", path.display()),
+            Error::InvalidOptions(msg) => write!(f, "Invalid record options: {}", msg),
+            Error::PermissionDenied => write!(f, "Permission denied to open perf events"),
+            Error::UnsupportedEvent => write!(f, "Event is not supported on this system"),
+            Error::RecordFailed => write!(f, "Failed to record trace"),
+            Error::InjectFailed => write!(f, "Failed to decode trace"),
+            Error::ReportFailed => write!(f, "Failed to summarize sampling profile"),
+            Error::InvalidBinaryFilter => write!(f, "Invalid binary filter"),
+            Error::Unknown(code) => write!(f, "Unknown simpleperf status code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Result type of simpleperf profcollect operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn status_to_result(status: c_int) -> Result<()> {
+    match status {
+        PROFCOLLECT_OK => Ok(()),
+        PROFCOLLECT_ERROR_PERMISSION => Err(Error::PermissionDenied),
+        PROFCOLLECT_ERROR_UNSUPPORTED_EVENT => Err(Error::UnsupportedEvent),
+        PROFCOLLECT_ERROR_RECORD => Err(Error::RecordFailed),
+        PROFCOLLECT_ERROR_INJECT => Err(Error::InjectFailed),
+        PROFCOLLECT_ERROR_REPORT => Err(Error::ReportFailed),
+        PROFCOLLECT_ERROR_INVALID_BINARY_FILTER => Err(Error::InvalidBinaryFilter),
+        code => Err(Error::Unknown(code)),
+    }
 }
 
-/// Returns whether the system has support for simpleperf etm.
-pub fn has_support() -> bool {
-    unsafe { simpleperf_profcollect_bindgen::HasSupport() }
+fn path_to_cstr(path: &Path) -> Result<CString> {
+    path.to_str()
+        .and_then(|s| CString::new(s).ok())
+        .ok_or_else(|| Error::InvalidPath(path.to_path_buf()))
+}
+
+/// Returns `Ok(())` if the system has support for simpleperf etm, or the reason why not.
+pub fn has_support() -> Result<()> {
+    status_to_result(unsafe { simpleperf_profcollect_bindgen::HasSupport() })
 }
 
 /// ETM recording scope
@@ -41,26 +117,81 @@ pub enum RecordScope {
 }
 
 /// Trigger an ETM trace event.
-pub fn record(trace_file: &Path, duration: &Duration, scope: RecordScope) {
-    let event_name: CString = match scope {
-        RecordScope::USERSPACE => CString::new("cs-etm:u").unwrap(),
-        RecordScope::KERNEL => CString::new("cs-etm:k").unwrap(),
-        RecordScope::BOTH => CString::new("cs-etm").unwrap(),
+pub fn record(
+    trace_file: &Path,
+    duration: &Duration,
+    scope: RecordScope,
+    options: &RecordOptions,
+) -> Result<()> {
+    let event_name = match scope {
+        RecordScope::USERSPACE => "cs-etm:u",
+        RecordScope::KERNEL => "cs-etm:k",
+        RecordScope::BOTH => "cs-etm",
     };
-    let trace_file = path_to_cstr(trace_file);
+    record_event(event_name, trace_file, duration, options)
+}
+
+fn event_to_cstr(event_name: &str) -> Result<CString> {
+    CString::new(event_name).map_err(|_| Error::UnsupportedEvent)
+}
+
+fn record_event(
+    event_name: &str,
+    output: &Path,
+    duration: &Duration,
+    options: &RecordOptions,
+) -> Result<()> {
+    let event_name = event_to_cstr(event_name)?;
+    let output = path_to_cstr(output)?;
     let duration = duration.as_secs_f32();
+    let etm_sink = CString::new(options.get_etm_sink()?).unwrap();
+    let args = options
+        .to_args()?
+        .into_iter()
+        .map(CString::new)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::InvalidOptions(e.to_string()))?;
+    let arg_ptrs: Vec<*const c_char> = args.iter().map(|a| a.as_ptr()).collect();
 
-    unsafe {
-        simpleperf_profcollect_bindgen::Record(event_name.as_ptr(), trace_file.as_ptr(), duration);
-    }
+    status_to_result(unsafe {
+        simpleperf_profcollect_bindgen::Record(
+            event_name.as_ptr(),
+            output.as_ptr(),
+            duration,
+            etm_sink.as_ptr(),
+            arg_ptrs.as_ptr(),
+            arg_ptrs.len(),
+        )
+    })
 }
 
-/// Translate ETM trace to profile.
-pub fn process(trace_path: &Path, profile_path: &Path) {
-    let trace_path = path_to_cstr(trace_path);
-    let profile_path = path_to_cstr(profile_path);
+/// Regexes selecting the binaries kept when translating a trace to a profile.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BinaryFilter {
+    /// Only keep binaries whose path matches this regex. None keeps all binaries.
+    pub include: Option<String>,
+    /// Drop binaries whose path matches this regex, even if they match `include`.
+    pub exclude: Option<String>,
+}
 
-    unsafe {
-        simpleperf_profcollect_bindgen::Inject(trace_path.as_ptr(), profile_path.as_ptr());
-    }
+fn filter_to_cstr(filter: &Option<String>) -> Result<CString> {
+    CString::new(filter.as_deref().unwrap_or("")).map_err(|_| Error::InvalidBinaryFilter)
+}
+
+/// Translate ETM trace to profile. Fails with `Error::InvalidBinaryFilter`, before reading the
+/// trace, if either regex of `binary_filter` is invalid.
+pub fn process(trace_path: &Path, profile_path: &Path, binary_filter: &BinaryFilter) -> Result<()> {
+    let trace_path = path_to_cstr(trace_path)?;
+    let profile_path = path_to_cstr(profile_path)?;
+    let include = filter_to_cstr(&binary_filter.include)?;
+    let exclude = filter_to_cstr(&binary_filter.exclude)?;
+
+    status_to_result(unsafe {
+        simpleperf_profcollect_bindgen::Inject(
+            trace_path.as_ptr(),
+            profile_path.as_ptr(),
+            include.as_ptr(),
+            exclude.as_ptr(),
+        )
+    })
 }
@@ -0,0 +1,299 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Options passed through to the simpleperf record command.
+
+use crate::{Error, Result};
+
+/// Processes to record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RecordTarget {
+    /// Record all processes on the system.
+    SystemWide,
+    /// Record only the given processes.
+    Pids(Vec<u32>),
+    /// Record all processes belonging to the given uid.
+    Uid(u32),
+    /// Record all processes with a name matching the given regex.
+    ProcessNameRegex(String),
+}
+
+/// How often samples are taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleRate {
+    /// Take at most this many samples per second.
+    Frequency(u64),
+    /// Take one sample every this many events.
+    Period(u64),
+}
+
+/// Call graph recording mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallGraph {
+    /// Do not record call graphs.
+    None,
+    /// Unwind using frame pointers.
+    FramePointer,
+    /// Unwind using dwarf debug frames, with an optional stack dump size in bytes.
+    Dwarf(Option<u32>),
+}
+
+/// Options for a simpleperf recording.
+///
+/// ```ignore
+/// let options = RecordOptions::default()
+///     .target(RecordTarget::ProcessNameRegex("surfaceflinger".to_string()))
+///     .cpus(&[0, 1, 2, 3])
+///     .sample_rate(SampleRate::Frequency(1000));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordOptions {
+    target: RecordTarget,
+    cpus: Vec<u32>,
+    exclude_pids: Vec<u32>,
+    sample_rate: Option<SampleRate>,
+    aux_buffer_size: Option<u64>,
+    etm_sink: Option<String>,
+    call_graph: CallGraph,
+}
+
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            target: RecordTarget::SystemWide,
+            cpus: Vec::new(),
+            exclude_pids: Vec::new(),
+            sample_rate: None,
+            aux_buffer_size: None,
+            etm_sink: None,
+            call_graph: CallGraph::None,
+        }
+    }
+}
+
+impl RecordOptions {
+    /// Set the processes to record. Defaults to system-wide.
+    pub fn target(mut self, target: RecordTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Only record on the given cpus. Defaults to all cpus.
+    pub fn cpus(mut self, cpus: &[u32]) -> Self {
+        self.cpus = cpus.to_vec();
+        self
+    }
+
+    /// Exclude samples from the given processes.
+    pub fn exclude_pids(mut self, pids: &[u32]) -> Self {
+        self.exclude_pids = pids.to_vec();
+        self
+    }
+
+    /// Set the sample frequency or period. Defaults to the simpleperf default for the event.
+    pub fn sample_rate(mut self, sample_rate: SampleRate) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Set the aux buffer size in bytes, only used by cs-etm events. Must be a power of two and
+    /// page size aligned.
+    pub fn aux_buffer_size(mut self, size: u64) -> Self {
+        self.aux_buffer_size = Some(size);
+        self
+    }
+
+    /// Select the ETM sink by name, as listed in /sys/bus/event_source/devices/cs_etm/sinks.
+    pub fn etm_sink(mut self, sink: &str) -> Self {
+        self.etm_sink = Some(sink.to_string());
+        self
+    }
+
+    /// Set the call graph recording mode. Defaults to no call graph.
+    pub fn call_graph(mut self, call_graph: CallGraph) -> Self {
+        self.call_graph = call_graph;
+        self
+    }
+
+    pub(crate) fn get_etm_sink(&self) -> Result<&str> {
+        match &self.etm_sink {
+            None => Ok(""),
+            Some(sink)
+                if !sink.is_empty()
+                    && sink.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+            {
+                Ok(sink)
+            }
+            Some(sink) => Err(Error::InvalidOptions(format!("Invalid ETM sink name: {}", sink))),
+        }
+    }
+
+    /// Convert the options to arguments of the record command.
+    pub(crate) fn to_args(&self) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        match &self.target {
+            RecordTarget::SystemWide => args.push("-a".to_string()),
+            RecordTarget::Pids(pids) => {
+                if pids.is_empty() {
+                    return Err(Error::InvalidOptions("Empty pid list".to_string()));
+                }
+                args.extend(["-p".to_string(), join(pids)]);
+            }
+            RecordTarget::Uid(uid) => {
+                args.extend(["-a".to_string(), "--include-uid".to_string(), uid.to_string()]);
+            }
+            RecordTarget::ProcessNameRegex(regex) => {
+                if regex.is_empty() {
+                    return Err(Error::InvalidOptions("Empty process name regex".to_string()));
+                }
+                args.extend([
+                    "-a".to_string(),
+                    "--include-process-name".to_string(),
+                    regex.clone(),
+                ]);
+            }
+        }
+        if !self.cpus.is_empty() {
+            args.extend(["--cpu".to_string(), join(&self.cpus)]);
+        }
+        if !self.exclude_pids.is_empty() {
+            args.extend(["--exclude-pid".to_string(), join(&self.exclude_pids)]);
+        }
+        match self.sample_rate {
+            Some(SampleRate::Frequency(freq)) => args.extend(["-f".to_string(), freq.to_string()]),
+            Some(SampleRate::Period(period)) => args.extend(["-c".to_string(), period.to_string()]),
+            None => (),
+        }
+        if let Some(size) = self.aux_buffer_size {
+            if !size.is_power_of_two() {
+                return Err(Error::InvalidOptions(format!(
+                    "Aux buffer size {} is not a power of two",
+                    size
+                )));
+            }
+            if size % page_size() != 0 {
+                return Err(Error::InvalidOptions(format!(
+                    "Aux buffer size {} is not page size aligned",
+                    size
+                )));
+            }
+            args.extend(["--aux-buffer-size".to_string(), size.to_string()]);
+        }
+        match self.call_graph {
+            CallGraph::None => (),
+            CallGraph::FramePointer => args.extend(["--call-graph".to_string(), "fp".to_string()]),
+            CallGraph::Dwarf(None) => {
+                args.extend(["--call-graph".to_string(), "dwarf".to_string()])
+            }
+            CallGraph::Dwarf(Some(size)) => {
+                args.extend(["--call-graph".to_string(), format!("dwarf,{}", size)])
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+fn join(values: &[u32]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(options: RecordOptions) -> Vec<String> {
+        options.to_args().unwrap()
+    }
+
+    #[test]
+    fn target_args() {
+        assert_eq!(args(RecordOptions::default()), vec!["-a"]);
+        assert_eq!(
+            args(RecordOptions::default().target(RecordTarget::Pids(vec![1, 23]))),
+            vec!["-p", "1,23"]
+        );
+        assert_eq!(
+            args(RecordOptions::default().target(RecordTarget::Uid(1000))),
+            vec!["-a", "--include-uid", "1000"]
+        );
+        assert_eq!(
+            args(
+                RecordOptions::default()
+                    .target(RecordTarget::ProcessNameRegex("surfaceflinger".to_string()))
+            ),
+            vec!["-a", "--include-process-name", "surfaceflinger"]
+        );
+    }
+
+    #[test]
+    fn empty_targets_rejected() {
+        let options = RecordOptions::default().target(RecordTarget::Pids(Vec::new()));
+        assert!(matches!(options.to_args(), Err(Error::InvalidOptions(_))));
+        let options = RecordOptions::default().target(RecordTarget::ProcessNameRegex("".into()));
+        assert!(matches!(options.to_args(), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn sampling_args() {
+        let options = RecordOptions::default()
+            .cpus(&[0, 2])
+            .exclude_pids(&[42])
+            .sample_rate(SampleRate::Period(10000))
+            .call_graph(CallGraph::Dwarf(Some(8192)));
+        assert_eq!(
+            args(options),
+            vec![
+                "-a",
+                "--cpu",
+                "0,2",
+                "--exclude-pid",
+                "42",
+                "-c",
+                "10000",
+                "--call-graph",
+                "dwarf,8192"
+            ]
+        );
+        let options = RecordOptions::default()
+            .sample_rate(SampleRate::Frequency(1000))
+            .call_graph(CallGraph::FramePointer);
+        assert_eq!(args(options), vec!["-a", "-f", "1000", "--call-graph", "fp"]);
+    }
+
+    #[test]
+    fn aux_buffer_size_must_be_aligned_power_of_two() {
+        let options = RecordOptions::default().aux_buffer_size(4 << 20);
+        assert_eq!(args(options), vec!["-a", "--aux-buffer-size", "4194304"]);
+        for size in &[0, 3 << 20, page_size() / 2] {
+            let options = RecordOptions::default().aux_buffer_size(*size);
+            assert!(matches!(options.to_args(), Err(Error::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn etm_sink_validated() {
+        assert_eq!(RecordOptions::default().get_etm_sink(), Ok(""));
+        assert_eq!(RecordOptions::default().etm_sink("tmc_etr0").get_etm_sink(), Ok("tmc_etr0"));
+        for sink in &["", "../etr", "tmc etr", "tmc,etr"] {
+            let options = RecordOptions::default().etm_sink(sink);
+            assert!(matches!(options.get_etm_sink(), Err(Error::InvalidOptions(_))), "{}", sink);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//
+// Copyright (C) 2021 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Sampling profiles with ordinary perf events, for systems without ETM support.
+
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{event_to_cstr, path_to_cstr, record_event, status_to_result, RecordOptions, Result};
+
+/// Event used to trigger samples.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SamplingEvent {
+    /// Software timer based on cpu time, available on any Linux system.
+    CpuClock,
+    /// Software timer based on the time a task runs.
+    TaskClock,
+    /// Hardware cpu cycle counter.
+    Cycles,
+    /// Any event listed by `simpleperf list`, optionally with a modifier, e.g. "instructions:u".
+    Other(String),
+}
+
+impl fmt::Display for SamplingEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SamplingEvent::CpuClock => write!(f, "cpu-clock"),
+            SamplingEvent::TaskClock => write!(f, "task-clock"),
+            SamplingEvent::Cycles => write!(f, "cpu-cycles"),
+            SamplingEvent::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Returns `Ok(())` if `event` can be recorded on this system, or the reason why not.
+pub fn has_sampling_support(event: &SamplingEvent) -> Result<()> {
+    let event_name = event_to_cstr(&event.to_string())?;
+    status_to_result(unsafe {
+        simpleperf_profcollect_bindgen::IsEventSupported(event_name.as_ptr())
+    })
+}
+
+/// Record a sampled profile into a perf.data file. Call graphs are recorded according to
+/// `options`.
+pub fn record_sampling(
+    perf_data: &Path,
+    duration: &Duration,
+    event: &SamplingEvent,
+    options: &RecordOptions,
+) -> Result<()> {
+    record_event(&event.to_string(), perf_data, duration, options)
+}
+
+/// Summarize a sampled perf.data file into a csv report. Each line of the report holds a binary,
+/// a virtual address in it, the accumulated and self event counts, and the sample count.
+pub fn process_sampling(perf_data: &Path, profile_path: &Path) -> Result<()> {
+    let perf_data = path_to_cstr(perf_data)?;
+    let profile_path = path_to_cstr(profile_path)?;
+
+    status_to_result(unsafe {
+        simpleperf_profcollect_bindgen::ReportSample(perf_data.as_ptr(), profile_path.as_ptr())
+    })
+}